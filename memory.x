@@ -22,7 +22,55 @@ _stack_end   = _stack_start - _stack_size;/* 0x2000_F000 */
    Heap layout (fixed size, grows up)
    ───────────────────────────────────────────── */
 
-/* Example: heap from 0x2000_0000 to 0x2000_0FFF → 4 KiB */
-_heap_start = ORIGIN(RAM) + 0x00001000;   /* 0x2000_0000 */
+/* Heap from 0x2000_1000 to 0x2000_1FFF → 4 KiB, above .data and .bss */
+_heap_start = ORIGIN(RAM) + 0x00001000;   /* 0x2000_1000 */
 _heap_size  = 0x00001000;                 /* 4 KiB */
-_heap_end   = _heap_start + _heap_size;   /* 0x2000_1000 */
+_heap_end   = _heap_start + _heap_size;   /* 0x2000_2000 */
+
+/* Regions the kernel heap is built from, as (start, size) pairs. Add a pair
+   per extra region, e.g. a second RAM bank, up to MAX_REGIONS in
+   src/kernel/allocator.rs. Regions must not overlap the stack, .data/.bss or
+   each other, the kernel checks that at boot. They may touch. */
+SECTIONS
+{
+  .heap_regions : ALIGN(4)
+  {
+    __heap_regions_start = .;
+    LONG(_heap_start); LONG(_heap_size);
+    __heap_regions_end = .;
+  } > FLASH
+} INSERT AFTER .rodata;
+
+/* ─────────────────────────────────────────────
+   Kernel state
+   ───────────────────────────────────────────── */
+
+/* Statics of the kernel, marked #[link_section = ".kernel.*"]. Threads can
+   not reach them once the MPU is on, except for the counters in
+   .kernel.shared, which they may read. See src/kernel/mpu.rs, the rest of the
+   kernel's data is on the heap. Placed after .bss, so it is zeroed at boot
+   like .bss: only zero initialised statics may go here. The alignment lets one
+   MPU region cover .data and .bss in front of it. */
+SECTIONS
+{
+  .kernel (NOLOAD) : ALIGN(1024)
+  {
+    __skernel = .;
+    *(.kernel.shared .kernel.shared.*);
+    . = ALIGN(32);
+    __ekernel_shared = .;
+    *(.kernel .kernel.*);
+    . = ALIGN(4);
+    __ekernel = .;
+  } > RAM
+} INSERT AFTER .bss;
+
+/* A single MPU region of at most 8 KiB, cut into eighths of at most 1 KiB,
+   covers the statics up to __skernel exactly, and one of 32 bytes covers the
+   shared counters. */
+ASSERT(__skernel - __sdata <= 0x2000, "
+ERROR(memory.x): .data and .bss exceed 8 KiB, the MPU region for them would reach into .kernel");
+ASSERT(__sdata % 0x2000 == 0, "
+ERROR(memory.x): .data does not start on an 8 KiB boundary, the MPU region for the statics would be off");
+ASSERT(__ekernel_shared - __skernel <= 32, "
+ERROR(memory.x): .kernel.shared exceeds 32 bytes, the MPU region for it would reach into .kernel");
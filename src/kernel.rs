@@ -1,4 +1,9 @@
 pub mod scheduler;
 pub mod thread;
 pub mod allocator;
-pub mod syscall;
\ No newline at end of file
+pub mod syscall;
+pub mod time;
+pub mod sync;
+pub mod timer;
+#[cfg(feature = "mpu")]
+pub mod mpu;
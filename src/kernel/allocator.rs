@@ -1,7 +1,263 @@
 extern crate alloc;
+use alloc::boxed::Box;
 use core::alloc::{GlobalAlloc, Layout};
 use core::ptr;
-use core::mem;
+use core::mem::{self, MaybeUninit};
+use cortex_m_semihosting::hprintln;
+#[cfg(feature = "mpu")]
+use crate::kernel::mpu;
+use crate::kernel::syscall::{decode, is_privileged, svc_call, Error, SysCall};
+use crate::kernel::thread::Tcb;
+use crate::list::{AllocError, Node};
+
+pub mod pool;
+#[cfg(feature = "self-test")]
+pub mod self_test;
+#[cfg(feature = "tlsf")]
+pub mod tlsf;
+
+/// Allocator of the kernel heap, picked with cargo features:
+/// - default: `LinkedListAllocator`, first fit
+/// - `best-fit`: `LinkedListAllocator`, smallest fitting region
+/// - `tlsf`: `TlsfAllocator`, constant time allocation and deallocation, takes
+///   precedence over `best-fit` so the features stay additive
+#[cfg(not(feature = "tlsf"))]
+pub type Heap = LinkedListAllocator;
+#[cfg(feature = "tlsf")]
+pub type Heap = tlsf::TlsfAllocator;
+
+/// The kernel heap. Only privileged code allocates from it directly, threads
+/// go through `SysCall::ALLOC` and `SysCall::FREE`.
+#[link_section = ".kernel.allocator"]
+pub static ALLOCATOR: Locked<Heap> = Locked::new(Heap::new());
+
+#[global_allocator]
+static GLOBAL_ALLOCATOR: SyscallAllocator = SyscallAllocator;
+
+/// Usage of a heap, see `stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Bytes managed by the heap
+    pub size: usize,
+    /// Bytes in free blocks
+    pub free: usize,
+    /// Size of the largest free block
+    pub largest_free: usize,
+    /// Blocks currently allocated
+    pub allocations: usize,
+    /// Most bytes ever allocated at once
+    pub high_water: usize,
+}
+
+/// Damage found by `check`. Addresses are those of the offending block or
+/// free list node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapError {
+    /// The block lies outside the heap
+    OutOfBounds(usize),
+    /// The size of the block is misaligned, too small or reaches past the end
+    /// of the heap
+    BadSize(usize),
+    /// The links to the block disagree with the heap layout, e.g. the free
+    /// list is out of order or loops back
+    BadLink(usize),
+    /// The block is free and so is the one in front of it, they should have
+    /// been merged
+    Unmerged(usize),
+    /// The free blocks do not add up to the bytes not allocated
+    FreeMismatch { counted: usize, expected: usize },
+    /// The heap was locked, e.g. the fault hit while allocating
+    Locked,
+}
+
+/// Allocation counters, kept by all allocators.
+struct Usage {
+    /// Bytes managed by the allocator
+    size: usize,
+    /// Bytes handed out, including padding and headers
+    used: usize,
+    allocations: usize,
+    high_water: usize,
+}
+
+impl Usage {
+    const fn new() -> Self {
+        Usage { size: 0, used: 0, allocations: 0, high_water: 0 }
+    }
+
+    fn allocated(&mut self, size: usize) {
+        self.used += size;
+        self.allocations += 1;
+        self.high_water = self.high_water.max(self.used);
+    }
+
+    fn freed(&mut self, size: usize) {
+        self.used -= size;
+        self.allocations -= 1;
+    }
+
+    fn stats(&self, largest_free: usize) -> HeapStats {
+        HeapStats {
+            size: self.size,
+            free: self.size - self.used,
+            largest_free,
+            allocations: self.allocations,
+            high_water: self.high_water,
+        }
+    }
+
+    /// Compares the bytes found in free blocks with the bytes not handed out.
+    fn check_free(&self, counted: usize) -> Result<(), HeapError> {
+        let expected = self.size - self.used;
+        if counted != expected {
+            return Err(HeapError::FreeMismatch { counted, expected });
+        }
+        Ok(())
+    }
+}
+
+/// Most memory regions a heap can manage.
+pub const MAX_REGIONS: usize = 4;
+
+/// Why a memory region was not added to a heap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionError {
+    /// The region can not hold a single block
+    TooSmall { start: usize, end: usize },
+    /// The heap already manages `MAX_REGIONS` regions
+    TooMany,
+    /// The region overlaps memory in use, i.e. another region of the heap,
+    /// the main stack or the statics.
+    Overlap { start: usize, end: usize },
+}
+
+/// Bounds of the regions a heap manages, for `check`.
+struct Regions {
+    bounds: [(usize, usize); MAX_REGIONS],
+    count: usize,
+}
+
+impl Regions {
+    const fn new() -> Self {
+        Regions { bounds: [(0, 0); MAX_REGIONS], count: 0 }
+    }
+
+    /// Records the region from `start` to `end`, unless it overlaps one
+    /// already there.
+    fn add(&mut self, start: usize, end: usize) -> Result<(), RegionError> {
+        if self.iter().any(|&(other_start, other_end)| start < other_end && other_start < end) {
+            return Err(RegionError::Overlap { start, end });
+        }
+        let slot = self.bounds.get_mut(self.count).ok_or(RegionError::TooMany)?;
+        *slot = (start, end);
+        self.count += 1;
+        Ok(())
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &(usize, usize)> {
+        self.bounds[..self.count].iter()
+    }
+
+    /// Bounds of the region `addr` lies in. Touching regions count as one,
+    /// the linked list allocator merges free blocks across them.
+    fn find(&self, addr: usize) -> Option<(usize, usize)> {
+        let (mut start, mut end) = self.iter().copied().find(|&(start, end)| start <= addr && addr < end)?;
+        // each pass joins at least one neighbour, there are at most count - 1
+        for _ in 1..self.count {
+            for &(other_start, other_end) in self.iter() {
+                if other_start == end {
+                    end = other_end;
+                }
+                if other_end == start {
+                    start = other_start;
+                }
+            }
+        }
+        Some((start, end))
+    }
+}
+
+/// A heap region as listed by the linker script.
+#[repr(C)]
+struct HeapRegion {
+    start: usize,
+    size: usize,
+}
+
+extern "C" {
+    // `.heap_regions` in memory.x
+    static __heap_regions_start: HeapRegion;
+    static __heap_regions_end: HeapRegion;
+    // the main stack, used by handlers and `main`
+    static _stack_start: u8;
+    static _stack_end: u8;
+    // .data, .bss and .uninit, laid out by cortex-m-rt
+    static __sdata: u8;
+    static __sheap: u8;
+}
+
+/// Start and end of the non-empty heap regions listed in memory.x.
+pub fn regions() -> impl Iterator<Item = (usize, usize)>
+{
+    let regions = &raw const __heap_regions_start;
+    let count = (&raw const __heap_regions_end as usize - regions as usize) / mem::size_of::<HeapRegion>();
+    (0..count)
+        // Note(unsafe): the table lies in flash and is never written.
+        .map(move |i| unsafe { &*regions.add(i) })
+        .filter(|region| region.size > 0)
+        .map(|region| (region.start, region.start + region.size))
+}
+
+/// Hands the heap regions listed in memory.x to `ALLOCATOR`. Fails on the
+/// first region that overlaps another one, the main stack or the statics.
+///
+/// # Safety
+/// Must be called once, before anything is allocated.
+pub unsafe fn init() -> Result<(), RegionError>
+{
+    let reserved = [
+        (&raw const _stack_end as usize, &raw const _stack_start as usize),
+        (&raw const __sdata as usize, &raw const __sheap as usize),
+    ];
+
+    let mut heap = ALLOCATOR.lock();
+    for (start, end) in regions() {
+        if reserved.iter().any(|&(other_start, other_end)| start < other_end && other_start < end) {
+            return Err(RegionError::Overlap { start, end });
+        }
+        unsafe { heap.add_region(start, end - start)? };
+    }
+    Ok(())
+}
+
+/// Usage of the kernel heap, `None` while it is locked.
+///
+/// Safe to call from threads, handlers and fault handlers.
+pub fn stats() -> Option<HeapStats>
+{
+    if is_privileged() {
+        return ALLOCATOR.try_lock().map(|heap| heap.stats());
+    }
+    let mut stats = MaybeUninit::<HeapStats>::uninit();
+    decode(svc_call(SysCall::HEAP_STATS, stats.as_mut_ptr() as usize, 0, 0)).ok()?;
+    // Note(unsafe): the kernel wrote the stats.
+    Some(unsafe { stats.assume_init() })
+}
+
+/// Walks the kernel heap and checks that its blocks and free lists are
+/// consistent.
+///
+/// Safe to call from threads, handlers and fault handlers.
+pub fn check() -> Result<(), HeapError>
+{
+    if is_privileged() {
+        return ALLOCATOR.try_lock().ok_or(HeapError::Locked)?.check();
+    }
+    let mut result = Err(HeapError::Locked);
+    svc_call(SysCall::HEAP_CHECK, &raw mut result as usize, 0, 0);
+    result
+}
+
 pub struct Locked<A> 
 {
     inner: spin::Mutex<A>,
@@ -14,9 +270,19 @@ impl<A> Locked<A> {
         }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
+    pub fn lock(&self) -> spin::MutexGuard<'_, A> {
         self.inner.lock()
     }
+
+    /// Locks unless someone holds the lock already.
+    pub fn try_lock(&self) -> Option<spin::MutexGuard<'_, A>> {
+        self.inner.try_lock()
+    }
+
+    /// True while someone holds the lock, i.e. `lock` would spin.
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
 }
 
 
@@ -24,6 +290,9 @@ fn align_up(addr: usize, align: usize) -> usize {
     (addr + align - 1) & !(align - 1)
 }
 
+/// Header of a free region. Aligned to 8 bytes, like all regions, so blocks
+/// with an alignment up to 8 never leave a gap too small for a node in front.
+#[repr(align(8))]
 struct ListNode {
     size: usize,
     next: Option<&'static mut ListNode>,
@@ -46,52 +315,141 @@ impl ListNode {
 
 pub struct LinkedListAllocator {
     head: ListNode,
+    regions: Regions,
+    usage: Usage,
 }
 
 
+impl Default for LinkedListAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl LinkedListAllocator {
     /// Creates an empty LinkedListAllocator.
     pub const fn new() -> Self 
     {
         Self {
             head: ListNode::new(0),
+            regions: Regions::new(),
+            usage: Usage::new(),
         }
     }
 
-    /// Initialize the allocator with the given heap bounds.
+    /// Adds the memory from `start` to `start + size` to the heap. Regions
+    /// must not overlap each other, touching ones are merged like freed
+    /// neighbours.
     ///
+    /// # Safety
     /// This function is unsafe because the caller must guarantee that the given
-    /// heap bounds are valid and that the heap is unused. This method must be
-    /// called only once.
-    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize)
+    /// memory is valid and unused.
+    pub unsafe fn add_region(&mut self, start: usize, size: usize) -> Result<(), RegionError>
     {
+        // regions are kept in multiples of the node alignment
+        let align = mem::align_of::<ListNode>();
+        let end = (start + size) & !(align - 1);
+        let start = align_up(start, align);
+        if end < start + mem::size_of::<ListNode>() {
+            return Err(RegionError::TooSmall { start, end });
+        }
+        self.regions.add(start, end)?;
         unsafe {
-            self.add_free_region(heap_start, heap_size);
+            self.add_free_region(start, end - start);
+        }
+        self.usage.size += end - start;
+        Ok(())
+    }
+
+    /// Current usage of the heap.
+    pub fn stats(&self) -> HeapStats
+    {
+        let mut largest_free = 0;
+        let mut node = self.head.next.as_deref();
+        while let Some(region) = node {
+            largest_free = largest_free.max(region.size);
+            node = region.next.as_deref();
+        }
+        self.usage.stats(largest_free)
+    }
+
+    /// Checks that every free list node lies inside a heap region, that the list
+    /// is sorted by address, which also rules out loops, and that the free
+    /// regions add up.
+    pub fn check(&self) -> Result<(), HeapError>
+    {
+        let align = mem::align_of::<ListNode>();
+        let mut free = 0;
+        let mut prev_end: Option<usize> = None;
+        let mut node = self.head.next.as_deref();
+        while let Some(region) = node {
+            let addr = region.start_addr();
+            let (_, end) = self.regions.find(addr).ok_or(HeapError::OutOfBounds(addr))?;
+            if !addr.is_multiple_of(align)
+                || region.size < mem::size_of::<ListNode>()
+                || !region.size.is_multiple_of(align)
+                || region.size > end - addr
+            {
+                return Err(HeapError::BadSize(addr));
+            }
+            match prev_end {
+                Some(end) if addr < end => return Err(HeapError::BadLink(addr)),
+                Some(end) if addr == end => return Err(HeapError::Unmerged(addr)),
+                _ => {}
+            }
+            free += region.size;
+            prev_end = Some(region.end_addr());
+            node = region.next.as_deref();
         }
+        self.usage.check_free(free)
     }
 
-    /// Adds the given memory region to the front of the list.
+    /// Returns the given memory region to the free list, which is kept sorted
+    /// by address. The region is merged with free neighbours directly before
+    /// and after it, so freed blocks do not fragment the heap.
     unsafe fn add_free_region(&mut self, addr: usize, size: usize) 
     {
         // ensure that the freed region is capable of holding ListNode
         let aligned_address = align_up(addr, mem::align_of::<ListNode>());
-        if size >= mem::size_of::<ListNode>()
+        let size = size.saturating_sub(aligned_address - addr);
+        if size < mem::size_of::<ListNode>()
         {
-            // create a new list node and append it at the start of the list
-            let mut node = ListNode::new(size);
+            return;
+        }
 
-            node.next = self.head.next.take();
+        unsafe {
+            // find the last free region in front of the new one
+            let head: *mut ListNode = &mut self.head;
+            let mut prev = head;
+            while let Some(next) = (*prev).next.as_deref_mut() {
+                if next.start_addr() > aligned_address {
+                    break;
+                }
+                prev = next;
+            }
 
-            let node_ptr = aligned_address as *mut ListNode;
+            // merge with the following region
+            let mut size = size;
+            let mut next = (*prev).next.take();
+            if let Some(following) = next.take_if(|next| aligned_address + size == next.start_addr()) {
+                size += following.size;
+                next = following.next.take();
+            }
 
-            unsafe {
-                node_ptr.write(node);
-                self.head.next = Some(&mut *node_ptr);
+            // merge with the preceding region or link in a new node
+            if prev != head && (*prev).end_addr() == aligned_address {
+                (*prev).size += size;
+                (*prev).next = next;
+            } else {
+                let node_ptr = aligned_address as *mut ListNode;
+                node_ptr.write(ListNode { size, next });
+                (*prev).next = Some(&mut *node_ptr);
             }
         }
-
     }
 
+    /// Removes the first free region the allocation fits into from the list.
+    #[cfg(not(feature = "best-fit"))]
     fn find_region(&mut self, size: usize, align: usize)
         -> Option<(&'static mut ListNode, usize)>
     {
@@ -99,7 +457,7 @@ impl LinkedListAllocator {
         let mut current = &mut self.head;
         // look for a large enough memory region in linked list
         while let Some(ref mut region) = current.next {
-            if let Ok(alloc_start) = Self::alloc_from_region(&region, size, align) 
+            if let Ok(alloc_start) = Self::alloc_from_region(region, size, align) 
             {
                 // region suitable for allocation -> remove node from list
                 let next = region.next.take();
@@ -116,11 +474,43 @@ impl LinkedListAllocator {
         None
     }
 
+    /// Removes the smallest free region the allocation fits into from the
+    /// list. Walks the whole list, but keeps large regions for large requests.
+    #[cfg(feature = "best-fit")]
+    fn find_region(&mut self, size: usize, align: usize)
+        -> Option<(&'static mut ListNode, usize)>
+    {
+        // (node in front of the region, allocation start, region size)
+        let mut best: Option<(*mut ListNode, usize, usize)> = None;
+        let mut prev: *mut ListNode = &mut self.head;
+        unsafe {
+            while let Some(region) = (*prev).next.as_deref_mut() {
+                if let Ok(alloc_start) = Self::alloc_from_region(region, size, align) {
+                    if best.is_none_or(|(_, _, best_size)| region.size < best_size) {
+                        best = Some((prev, alloc_start, region.size));
+                    }
+                }
+                prev = region;
+            }
+
+            let (prev, alloc_start, _) = best?;
+            let region = (*prev).next.take().unwrap();
+            (*prev).next = region.next.take();
+            Some((region, alloc_start))
+        }
+    }
+
     /// Returns the allocation start address on success.
     fn alloc_from_region(region: &ListNode, size: usize, align: usize)
         -> Result<usize, ()>
     {
-        let alloc_start = align_up(region.start_addr(), align);
+        let mut alloc_start = align_up(region.start_addr(), align);
+        let gap = alloc_start - region.start_addr();
+        if gap > 0 && gap < mem::size_of::<ListNode>()
+        {
+            // leave room for the node that returns the gap to the free list
+            alloc_start = align_up(region.start_addr() + mem::size_of::<ListNode>(), align);
+        }
         let alloc_end = alloc_start.checked_add(size).ok_or(())?;
 
         if alloc_end > region.end_addr() 
@@ -145,46 +535,288 @@ impl LinkedListAllocator {
         Ok(alloc_start)
     }
     
-    /// Returns the adjusted size and alignment as a (size, align) tuple.
-    fn size_align(layout: Layout) -> (usize, usize) 
+    /// Returns the adjusted size and alignment as a (size, align) tuple, `None`
+    /// if the padded size overflows.
+    fn size_align(layout: Layout) -> Option<(usize, usize)>
     {
         let layout = layout
             .align_to(mem::align_of::<ListNode>())
-            .expect("adjusting alignment failed")
+            .ok()?
             .pad_to_align();
         let size = layout.size().max(mem::size_of::<ListNode>());
-        (size, layout.align())
+        Some((size, layout.align()))
     }
 }
 
 unsafe impl GlobalAlloc for Locked<LinkedListAllocator> 
 {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        // perform layout adjustme
-
-        let (size, align) = LinkedListAllocator::size_align(layout);
+        // perform layout adjustments
+        let (size, align) = match LinkedListAllocator::size_align(layout) {
+            Some(adjusted) => adjusted,
+            None => return ptr::null_mut(),
+        };
         let mut allocator = self.lock();
 
         if let Some((region, alloc_start)) = allocator.find_region(size, align) {
-            let alloc_end = alloc_start.checked_add(size).expect("overflow");
+            // `alloc_from_region` checked that the allocation fits the region
+            let alloc_end = alloc_start + size;
+            let (region_start, region_end) = (region.start_addr(), region.end_addr());
 
-        
-            let excess_size = region.end_addr() - alloc_end;
-            if excess_size > 0 {
-                unsafe {
-                    allocator.add_free_region(alloc_end, excess_size);
+            // return the parts in front of and behind the allocation
+            unsafe {
+                if alloc_start > region_start {
+                    allocator.add_free_region(region_start, alloc_start - region_start);
+                }
+                if region_end > alloc_end {
+                    allocator.add_free_region(alloc_end, region_end - alloc_end);
                 }
             }
-            return alloc_start as *mut u8;
+            allocator.usage.allocated(size);
+            alloc_start as *mut u8
         } else {
-            return ptr::null_mut();
+            ptr::null_mut()
         }
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        // perform layout adjustments
-        let (size, _) = LinkedListAllocator::size_align(layout);
+        // perform layout adjustments, they worked when the block was allocated
+        let (size, _) = match LinkedListAllocator::size_align(layout) {
+            Some(adjusted) => adjusted,
+            None => return,
+        };
+
+        let mut allocator = self.lock();
+        allocator.usage.freed(size);
+        unsafe { allocator.add_free_region(ptr as usize, size) }
+    }
+}
+
+/// Called when the kernel heap can not serve a request, with the id of the
+/// thread that asked, `None` for the kernel itself, and the requested layout.
+/// Runs in the kernel, with the heap unlocked.
+pub type AllocFailureHook = fn(thread: Option<usize>, layout: Layout);
+
+/// `None` until a hook is set, `report_alloc_failure` is used then.
+#[link_section = ".kernel.alloc_failure_hook"]
+static mut ALLOC_FAILURE_HOOK: Option<AllocFailureHook> = None;
+
+/// Replaces the hook called on allocation failures, `report_alloc_failure`
+/// by default. Threads get `Error::NotPrivileged`.
+///
+/// The hook only reports, the allocation still fails. `alloc::` collections
+/// then end up in `handle_alloc_error`, which panics. Code that can cope with
+/// a full heap uses the fallible APIs, e.g. `Vec::try_reserve`.
+pub fn set_alloc_failure_hook(hook: AllocFailureHook) -> Result<(), Error>
+{
+    if !is_privileged() {
+        return Err(Error::NotPrivileged);
+    }
+    cortex_m::interrupt::free(|_| unsafe { ALLOC_FAILURE_HOOK = Some(hook) });
+    Ok(())
+}
+
+/// Default allocation failure hook, prints the thread and the layout.
+pub fn report_alloc_failure(thread: Option<usize>, layout: Layout)
+{
+    match thread {
+        Some(id) => hprintln!(
+            "thread {}: allocating {} bytes aligned to {} failed",
+            id, layout.size(), layout.align()
+        ),
+        None => hprintln!(
+            "kernel: allocating {} bytes aligned to {} failed",
+            layout.size(), layout.align()
+        ),
+    }
+}
+
+pub(crate) fn alloc_failed(thread: Option<usize>, layout: Layout)
+{
+    // Note(unsafe): only written with interrupts disabled.
+    let hook = unsafe { ALLOC_FAILURE_HOOK };
+    hook.unwrap_or(report_alloc_failure)(thread, layout);
+}
+
+/// Routes `alloc::` collections to the kernel heap.
+///
+/// Unprivileged threads cannot take the heap lock safely, a preempted thread
+/// would keep it locked. Their requests are handed to the kernel by a syscall
+/// instead. Privileged code, i.e. `main` before the scheduler starts and
+/// exception handlers, uses the heap directly.
+///
+/// Every block handed out is recorded for its owner, see `Allocation`: the
+/// thread that allocated it, or the kernel for privileged code. Only the owner
+/// frees a block, other threads get `Error::NotOwner` from `SysCall::FREE`.
+/// Blocks a thread did not free are returned to the heap once it exited. With
+/// the `mpu` feature only the owner can reach a block, and a thread holds at
+/// most `mpu::GRANTS` blocks at a time, see `alloc_recorded`.
+pub struct SyscallAllocator;
+
+unsafe impl GlobalAlloc for SyscallAllocator
+{
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if is_privileged() {
+            return alloc_recorded(None, layout);
+        }
+        svc_call(SysCall::ALLOC, layout.size(), layout.align(), 0) as *mut u8
+    }
 
-        unsafe { self.lock().add_free_region(ptr as usize, size) }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if is_privileged() {
+            // kernel data is not recorded, see `kernel_node`
+            let layout = unsafe { unrecord(&raw mut ALLOCATIONS, ptr) }
+                .map_or(layout, |allocation| allocation.layout);
+            return unsafe { ALLOCATOR.dealloc(ptr, layout) };
+        }
+        let ret = svc_call(SysCall::FREE, ptr as usize, 0, 0);
+        debug_assert_eq!(ret, 0, "freed a block the thread did not allocate");
     }
-}
\ No newline at end of file
+}
+
+/// Record of a block handed out by `SyscallAllocator`. Records are kept in
+/// kernel memory, apart from the blocks, in a list per owner. They let
+/// `free_recorded` tell the owner's blocks from arbitrary pointers.
+pub(crate) struct Allocation {
+    block: *mut u8,
+    layout: Layout,
+    next: *mut Allocation,
+}
+
+/// Records of the blocks privileged code allocated, the most recent first.
+/// Threads keep theirs in `Tcb::allocations`.
+#[link_section = ".kernel.allocations"]
+static mut ALLOCATIONS: *mut Allocation = ptr::null_mut();
+
+/// Allocates a block from the kernel heap and records it. `owner` is the
+/// thread that asked, `None` for privileged code.
+///
+/// With the `mpu` feature the block is granted to its owner, which has to be
+/// the running thread. The grant takes one of the thread's regions, the
+/// allocation fails without one, and rounds the block up as `mpu::block_layout`
+/// says.
+pub(crate) fn alloc_recorded(mut owner: Option<&mut Tcb>, layout: Layout) -> *mut u8
+{
+    #[cfg(feature = "mpu")]
+    let layout = match owner.as_deref() {
+        Some(thread) => match mpu::block_layout(layout) {
+            Some(layout) if thread.regions.contains(&None) => layout,
+            _ => return ptr::null_mut(),
+        },
+        None => layout,
+    };
+    let id = owner.as_ref().map(|thread| thread.id);
+    let block = unsafe {
+        let block = ALLOCATOR.alloc(layout);
+        if block.is_null() {
+            alloc_failed(id, layout);
+            return ptr::null_mut();
+        }
+        let record = ALLOCATOR.alloc(Layout::new::<Allocation>()) as *mut Allocation;
+        if record.is_null() {
+            ALLOCATOR.dealloc(block, layout);
+            alloc_failed(id, layout);
+            return ptr::null_mut();
+        }
+        let list = match owner.as_mut() {
+            Some(thread) => &raw mut thread.allocations,
+            None => &raw mut ALLOCATIONS,
+        };
+        cortex_m::interrupt::free(|_| {
+            record.write(Allocation { block, layout, next: *list });
+            *list = record;
+        });
+        block
+    };
+    #[cfg(feature = "mpu")]
+    if let Some(thread) = owner {
+        mpu::grant_block(thread, block as usize, layout.size());
+    }
+    block
+}
+
+/// Frees a block `owner` got from `alloc_recorded`. Returns false, and leaves
+/// the heap alone, if the block is not one of its own. With the `mpu` feature
+/// the block is taken back from the owner.
+pub(crate) fn free_recorded(owner: &mut Tcb, block: *mut u8) -> bool
+{
+    match unsafe { unrecord(&raw mut owner.allocations, block) } {
+        Some(allocation) => {
+            unsafe { ALLOCATOR.dealloc(block, allocation.layout) };
+            #[cfg(feature = "mpu")]
+            mpu::revoke_block(owner, block as usize, allocation.layout.size());
+            true
+        }
+        None => false,
+    }
+}
+
+/// Frees the blocks of a thread that exited, together with their records. Only
+/// the kernel still sees the thread, the list needs no critical section.
+pub(crate) fn free_all_recorded(owner: &mut Tcb)
+{
+    while !owner.allocations.is_null() {
+        unsafe {
+            let allocation = owner.allocations.read();
+            ALLOCATOR.dealloc(owner.allocations as *mut u8, Layout::new::<Allocation>());
+            ALLOCATOR.dealloc(allocation.block, allocation.layout);
+            owner.allocations = allocation.next;
+        }
+    }
+}
+
+/// Removes the record of `block` from `list`, `None` if there is none.
+///
+/// # Safety
+/// `list` points to the head of a record list, which is only changed with
+/// interrupts disabled.
+unsafe fn unrecord(list: *mut *mut Allocation, block: *mut u8) -> Option<Allocation>
+{
+    let record = cortex_m::interrupt::free(|_| unsafe {
+        let mut link = list;
+        while !(*link).is_null() && (**link).block != block {
+            link = &raw mut (**link).next;
+        }
+        let record = *link;
+        if !record.is_null() {
+            *link = (*record).next;
+        }
+        record
+    });
+    if record.is_null() {
+        return None;
+    }
+    unsafe {
+        let allocation = record.read();
+        ALLOCATOR.dealloc(record as *mut u8, Layout::new::<Allocation>());
+        Some(allocation)
+    }
+}
+
+/// `Node::try_boxed` for kernel data. The node is not recorded, so threads
+/// can not free it. A failure is reported for `thread`, the one the kernel
+/// allocates for, `None` if privileged code asked.
+pub(crate) fn kernel_node<T>(thread: Option<usize>, element: T) -> Result<Box<Node<T>>, AllocError>
+{
+    let layout = Layout::new::<Node<T>>();
+    unsafe {
+        let node = ALLOCATOR.alloc(layout) as *mut Node<T>;
+        if node.is_null() {
+            alloc_failed(thread, layout);
+            return Err(AllocError);
+        }
+        node.write(Node::new(element));
+        Ok(Box::from_raw(node))
+    }
+}
+
+/// Drops a node from `kernel_node`. Unlike dropping the box this does not look
+/// for a record first, kernel nodes have none.
+pub(crate) fn free_kernel_node<T>(node: Box<Node<T>>)
+{
+    let node = Box::into_raw(node);
+    unsafe {
+        ptr::drop_in_place(node);
+        ALLOCATOR.dealloc(node as *mut u8, Layout::new::<Node<T>>());
+    }
+}
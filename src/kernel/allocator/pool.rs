@@ -0,0 +1,283 @@
+//! Fixed-size block pools for drivers and message buffers.
+//!
+//! A `Pool<T, N>` hands out `N` blocks that each hold one `T`. Taking and
+//! returning a block takes the same few instructions no matter how full the
+//! pool is, so hot paths and interrupt handlers do not have to touch the
+//! kernel heap. Threads may block while the pool is empty.
+
+use core::cell::{Cell, UnsafeCell};
+use core::mem::{self, ManuallyDrop, MaybeUninit};
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+use cortex_m::peripheral::SCB;
+use crate::kernel::scheduler::{Scheduler, SCHEDULER};
+use crate::kernel::sync::{deadline, encode_timeout, Timeout};
+use crate::kernel::syscall::{decode, is_privileged, svc_call, Error, SysCall};
+
+/// Type erased part of a `Pool` the kernel works on. It is followed in memory
+/// by one byte per block that is set while the block is taken, and then by the
+/// blocks, `buf_offset` bytes from its start.
+///
+/// Returned blocks form a list linked through their first word. Blocks that
+/// were never handed out are not on the list, they are taken from the end of
+/// the storage while `unused` is not 0, so a pool needs no setup.
+#[repr(C)]
+pub struct RawPool {
+    /// Address of the last returned block, 0 if the list is empty
+    free: Cell<usize>,
+    /// Blocks never handed out, they are the last `unused` blocks
+    unused: Cell<usize>,
+    /// Blocks that can be taken right away
+    available: Cell<usize>,
+    capacity: usize,
+    block_size: usize,
+    buf_offset: usize,
+}
+
+// Note(unsafe): the state is only touched by the kernel, in a service or with
+// interrupts disabled.
+unsafe impl Sync for RawPool {}
+
+impl RawPool {
+    const fn new(capacity: usize, block_size: usize, block_align: usize) -> Self {
+        RawPool {
+            free: Cell::new(0),
+            unused: Cell::new(capacity),
+            available: Cell::new(capacity),
+            capacity,
+            block_size,
+            buf_offset: (mem::size_of::<RawPool>() + capacity + block_align - 1) & !(block_align - 1),
+        }
+    }
+
+    fn key(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn block(&self, index: usize) -> usize {
+        self.key() + self.buf_offset + index * self.block_size
+    }
+
+    /// True if `addr` is the start of one of the blocks.
+    fn is_block(&self, addr: usize) -> bool {
+        let start = self.block(0);
+        addr >= start && addr < self.block(self.capacity) && (addr - start).is_multiple_of(self.block_size)
+    }
+
+    /// Flag of the block at `addr`, which `is_block`, 1 while it is taken.
+    fn taken(&self, addr: usize) -> &Cell<u8> {
+        let index = (addr - self.block(0)) / self.block_size;
+        // Note(unsafe): the flags follow the pool, one per block.
+        unsafe { &*((self.key() + mem::size_of::<RawPool>() + index) as *const Cell<u8>) }
+    }
+
+    /// Bytes the pool takes with its blocks, `None` if its state does not add
+    /// up. The pool lies in thread memory, services check it with this before
+    /// they touch the blocks.
+    pub(crate) fn extent(&self) -> Option<usize> {
+        if self.block_size < mem::size_of::<usize>()
+            || self.unused.get() > self.capacity
+            || self.available.get() > self.capacity
+        {
+            return None;
+        }
+        self.capacity.checked_mul(self.block_size)?.checked_add(self.buf_offset)
+    }
+
+    /// Takes a block from the pool, blocking at most `timeout` ticks while it
+    /// is empty. Exception handlers and privileged code never block.
+    fn acquire(&self, timeout: Timeout) -> Result<*mut u8, Error> {
+        let ret = if is_privileged() {
+            cortex_m::interrupt::free(|_| {
+                let scheduler = unsafe { &mut *(&raw mut SCHEDULER as *mut Scheduler) };
+                self.take(scheduler, 0)
+            })
+        } else {
+            decode(svc_call(SysCall::POOL_ALLOC, self.key(), encode_timeout(timeout), 0))
+        };
+        ret.map(|block| block as *mut u8)
+    }
+
+    /// Returns a block taken with `acquire`.
+    fn release(&self, block: *mut u8) {
+        let ret = if is_privileged() {
+            cortex_m::interrupt::free(|_| {
+                let scheduler = unsafe { &mut *(&raw mut SCHEDULER as *mut Scheduler) };
+                self.give(scheduler, block as usize)
+            })
+        } else {
+            decode(svc_call(SysCall::POOL_FREE, self.key(), block as usize, 0))
+        };
+        debug_assert!(ret.is_ok(), "returned a block of another pool or one that is free");
+    }
+
+    /// Kernel side of `acquire`, returns the address of the block.
+    pub(crate) fn take(&self, scheduler: &mut Scheduler, timeout: usize) -> Result<usize, Error> {
+        let free = self.free.get();
+        if free != 0 {
+            if !self.is_block(free) {
+                return Err(Error::InvalidArgument);
+            }
+            // Note(unsafe): returned blocks hold the address of the next one.
+            self.free.set(unsafe { *(free as *const usize) });
+            self.available.set(self.available.get() - 1);
+            self.taken(free).set(1);
+            return Ok(free);
+        }
+        let unused = self.unused.get();
+        if unused > 0 {
+            let block = self.block(self.capacity - unused);
+            self.unused.set(unused - 1);
+            self.available.set(self.available.get() - 1);
+            self.taken(block).set(1);
+            return Ok(block);
+        }
+
+        let wake_at = deadline(timeout).ok_or(Error::WouldBlock)?;
+        if scheduler.current_thread.is_none() {
+            return Err(Error::WouldBlock);
+        }
+        scheduler.block_current(self.key(), wake_at, Error::Timeout.code());
+        SCB::set_pendsv();
+        Ok(0)
+    }
+
+    /// Kernel side of `release`, hands the block straight to the most
+    /// important waiting thread, which takes it over, or puts it back on the
+    /// list. Refuses blocks that are not taken, returning one twice would link
+    /// the list into a cycle.
+    pub(crate) fn give(&self, scheduler: &mut Scheduler, block: usize) -> Result<usize, Error> {
+        if !self.is_block(block) || self.taken(block).get() != 1 {
+            return Err(Error::InvalidArgument);
+        }
+
+        if !scheduler.wake_one(self.key(), |_| block) {
+            // Note(unsafe): the block is unused and large enough for a word.
+            unsafe { *(block as *mut usize) = self.free.get() };
+            self.free.set(block);
+            self.available.set(self.available.get() + 1);
+            self.taken(block).set(0);
+        }
+        scheduler.reschedule();
+        Ok(0)
+    }
+}
+
+/// Storage of one block, large enough for the free list link.
+#[repr(C)]
+union Block<T> {
+    _next: usize,
+    _value: ManuallyDrop<T>,
+}
+
+/// Pool of `N` blocks for values of type `T`:
+/// ```ignore
+/// static BUFFERS: Pool<[u8; 64], 8> = Pool::new();
+///
+/// // in a driver thread, waits for a buffer to come back
+/// let mut buf = BUFFERS.alloc([0; 64], None).map_err(|e| e.error)?;
+///
+/// // in an interrupt handler, never waits
+/// if let Ok(buf) = BUFFERS.alloc_from_isr([0; 64]) { ... }
+/// ```
+/// Blocks go back to the pool when their `PoolBox` is dropped, in a thread or
+/// an interrupt handler.
+#[repr(C)]
+pub struct Pool<T, const N: usize> {
+    raw: RawPool,
+    /// Set while the block with the same index is taken, see `RawPool`
+    taken: [Cell<u8>; N],
+    buf: UnsafeCell<[MaybeUninit<Block<T>>; N]>,
+}
+
+unsafe impl<T: Send, const N: usize> Sync for Pool<T, N> {}
+
+/// An `alloc` that failed, hands the value back.
+#[derive(Debug)]
+pub struct PoolError<T> {
+    pub value: T,
+    /// `Error::WouldBlock` if the pool was empty and the call must not block,
+    /// `Error::Timeout` if it stayed empty
+    pub error: Error,
+}
+
+impl<T, const N: usize> Pool<T, N> {
+    pub const fn new() -> Self {
+        Pool {
+            raw: RawPool::new(N, mem::size_of::<Block<T>>(), mem::align_of::<Block<T>>()),
+            taken: [const { Cell::new(0) }; N],
+            buf: UnsafeCell::new([const { MaybeUninit::uninit() }; N]),
+        }
+    }
+
+    /// Moves `value` into a block, blocking at most `timeout` ticks while the
+    /// pool is empty.
+    pub fn alloc(&self, value: T, timeout: Timeout) -> Result<PoolBox<'_, T>, PoolError<T>> {
+        match self.raw.acquire(timeout) {
+            Ok(block) => {
+                let block = block as *mut T;
+                // Note(unsafe): the block is ours and fits a `T`.
+                unsafe { block.write(value) };
+                Ok(PoolBox { pool: &self.raw, value: block })
+            }
+            Err(error) => Err(PoolError { value, error }),
+        }
+    }
+
+    /// Moves `value` into a block if there is one, fails with
+    /// `Error::WouldBlock` otherwise.
+    pub fn try_alloc(&self, value: T) -> Result<PoolBox<'_, T>, PoolError<T>> {
+        self.alloc(value, Some(0))
+    }
+
+    /// `try_alloc` for exception handlers, which can not block.
+    pub fn alloc_from_isr(&self, value: T) -> Result<PoolBox<'_, T>, PoolError<T>> {
+        self.try_alloc(value)
+    }
+
+    /// Blocks that can be taken without waiting.
+    pub fn available(&self) -> usize {
+        self.raw.available.get()
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+}
+
+impl<T, const N: usize> Default for Pool<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A value in a block of a `Pool`, the block goes back to the pool when the
+/// box is dropped.
+pub struct PoolBox<'a, T> {
+    pool: &'a RawPool,
+    value: *mut T,
+}
+
+unsafe impl<T: Send> Send for PoolBox<'_, T> {}
+unsafe impl<T: Sync> Sync for PoolBox<'_, T> {}
+
+impl<T> Deref for PoolBox<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.value }
+    }
+}
+
+impl<T> DerefMut for PoolBox<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.value }
+    }
+}
+
+impl<T> Drop for PoolBox<'_, T> {
+    fn drop(&mut self) {
+        unsafe { ptr::drop_in_place(self.value) };
+        self.pool.release(self.value as *mut u8);
+    }
+}
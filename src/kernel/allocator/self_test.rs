@@ -0,0 +1,78 @@
+//! Boot time test of the heap allocator, built with the `self-test` feature:
+//! ```console
+//! $ cargo run --features self-test
+//! ```
+//! Runs on the target before the scheduler starts and halts with a panic on
+//! failure. Tests the allocator the features selected for the kernel heap.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+use cortex_m_semihosting::hprintln;
+use super::{Heap, Locked};
+
+const ARENA_SIZE: usize = 1024;
+
+/// Sizes allocated in every round, odd ones included to exercise the padding.
+const SIZES: [usize; 8] = [8, 24, 100, 3, 64, 200, 17, 40];
+
+const ROUNDS: usize = 100;
+
+#[repr(align(8))]
+struct Arena([u8; ARENA_SIZE]);
+
+static mut ARENA: Arena = Arena([0; ARENA_SIZE]);
+
+pub fn run()
+{
+    let heap = Locked::new(Heap::new());
+    unsafe { heap.lock().add_region(&raw mut ARENA.0 as usize, ARENA_SIZE).unwrap() };
+    let capacity = largest_block(&heap);
+
+    let mut blocks = [(ptr::null_mut(), Layout::new::<u8>()); SIZES.len()];
+    for round in 0..ROUNDS {
+        for (i, &size) in SIZES.iter().enumerate() {
+            let align = if (round + i) % 3 == 0 { 16 } else { 4 };
+            let layout = Layout::from_size_align(size, align).unwrap();
+            let block = unsafe { heap.alloc(layout) };
+            assert!(!block.is_null(), "round {}: allocating {} bytes failed", round, size);
+            assert_eq!(block as usize % align, 0, "misaligned block");
+            blocks[i] = (block, layout);
+        }
+        assert_eq!(heap.lock().check(), Ok(()), "round {}: heap damaged", round);
+        assert_eq!(heap.lock().stats().allocations, SIZES.len());
+
+        // free in an order that leaves holes, so merging is needed both ways
+        let step = if round % 2 == 0 { 3 } else { 5 };
+        for i in 0..SIZES.len() {
+            let (block, layout) = blocks[i * step % SIZES.len()];
+            unsafe { heap.dealloc(block, layout) };
+        }
+
+        // everything was merged back into a single free region
+        assert_eq!(largest_block(&heap), capacity, "round {}: heap fragmented", round);
+        let stats = heap.lock().stats();
+        assert_eq!(heap.lock().check(), Ok(()), "round {}: heap damaged", round);
+        assert_eq!((stats.allocations, stats.free), (0, stats.size));
+        assert_eq!(stats.largest_free, stats.size, "round {}: heap fragmented", round);
+    }
+
+    hprintln!("heap self test passed ({} rounds)", ROUNDS);
+}
+
+/// Size of the largest block `heap` can hand out.
+fn largest_block(heap: &Locked<Heap>) -> usize
+{
+    let (mut fits, mut too_large) = (0, ARENA_SIZE + 1);
+    while too_large - fits > 1 {
+        let size = (fits + too_large) / 2;
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        let block = unsafe { heap.alloc(layout) };
+        if block.is_null() {
+            too_large = size;
+        } else {
+            unsafe { heap.dealloc(block, layout) };
+            fits = size;
+        }
+    }
+    fits
+}
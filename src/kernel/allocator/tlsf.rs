@@ -0,0 +1,421 @@
+//! Two-level segregated fit allocator (TLSF), enabled with the `tlsf` feature.
+//!
+//! Free blocks are kept in size classes: the first level splits sizes by
+//! powers of two, the second level splits each power of two into
+//! `SL_COUNT` ranges. Two bitmaps tell which classes have free blocks, so
+//! finding a block and freeing one take the same few instructions no matter
+//! how many blocks there are.
+//!
+//! Every block starts with a header that links it to the block physically in
+//! front of it. A free neighbour is merged on `dealloc`, so no two free blocks
+//! are ever adjacent. A used block of size 0 marks the end of the heap.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem;
+use core::ptr;
+use super::{align_up, HeapError, HeapStats, Locked, RegionError, Regions, Usage};
+
+/// Alignment and granularity of all blocks.
+const ALIGN: usize = 8;
+const ALIGN_LOG2: u32 = 3;
+
+/// Second level classes per power of two.
+const SL_LOG2: u32 = 4;
+const SL_COUNT: usize = 1 << SL_LOG2;
+
+/// Blocks below this size all go into the first class of the first level, in
+/// steps of `ALIGN`.
+const FL_SHIFT: u32 = SL_LOG2 + ALIGN_LOG2;
+const SMALL_BLOCK: usize = 1 << FL_SHIFT;
+
+/// Blocks are smaller than 2^(FL_MAX + 1) bytes, larger regions are split.
+const FL_MAX: u32 = 20;
+const FL_COUNT: usize = (FL_MAX - FL_SHIFT + 2) as usize;
+const MAX_BLOCK: usize = (1 << (FL_MAX + 1)) - ALIGN;
+
+/// Bit 0 of `Block::size`, set while the block is free
+const FREE: usize = 1;
+
+#[repr(C)]
+struct Block {
+    /// Block physically in front of this one, null for the first block
+    prev_phys: *mut Block,
+    /// Size including the header, see `FREE`
+    size: usize,
+    /// Free list of the size class, only valid while the block is free
+    next_free: *mut Block,
+    prev_free: *mut Block,
+}
+
+/// Bytes in front of the payload of a used block.
+const HEADER: usize = 2 * mem::size_of::<usize>();
+
+/// Smallest block, it has to hold the free list links when freed.
+const MIN_BLOCK: usize = mem::size_of::<Block>();
+
+impl Block {
+    fn size(&self) -> usize {
+        self.size & !FREE
+    }
+
+    fn is_free(&self) -> bool {
+        self.size & FREE != 0
+    }
+
+    unsafe fn next_phys(block: *mut Block) -> *mut Block {
+        unsafe { (block as usize + (*block).size()) as *mut Block }
+    }
+
+    fn payload(block: *mut Block) -> *mut u8 {
+        (block as usize + HEADER) as *mut u8
+    }
+
+    fn from_payload(ptr: *mut u8) -> *mut Block {
+        (ptr as usize - HEADER) as *mut Block
+    }
+}
+
+/// Size class of a block of `size` bytes.
+fn mapping(size: usize) -> (usize, usize) {
+    if size < SMALL_BLOCK {
+        return (0, size >> ALIGN_LOG2);
+    }
+    let fl = usize::BITS - 1 - size.leading_zeros();
+    let sl = (size >> (fl - SL_LOG2)) & (SL_COUNT - 1);
+    ((fl - FL_SHIFT + 1) as usize, sl)
+}
+
+/// Size class whose blocks are all at least `size` bytes.
+fn mapping_search(size: usize) -> (usize, usize) {
+    if size < SMALL_BLOCK {
+        return mapping(size);
+    }
+    let fl = usize::BITS - 1 - size.leading_zeros();
+    mapping(size.saturating_add((1 << (fl - SL_LOG2)) - 1))
+}
+
+pub struct TlsfAllocator {
+    /// Bit n is set while `sl_bitmap[n]` is not 0
+    fl_bitmap: u32,
+    /// Bit m of entry n is set while `heads[n][m]` is not null
+    sl_bitmap: [u32; FL_COUNT],
+    heads: [[*mut Block; SL_COUNT]; FL_COUNT],
+    /// Each region runs from its first block to the end of its end marker
+    regions: Regions,
+    usage: Usage,
+}
+
+// Note(unsafe): the blocks are only reachable through the allocator, which
+// sits behind the lock of `Locked`.
+unsafe impl Send for TlsfAllocator {}
+
+impl Default for TlsfAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TlsfAllocator {
+    /// Creates an empty TlsfAllocator.
+    pub const fn new() -> Self {
+        TlsfAllocator {
+            fl_bitmap: 0,
+            sl_bitmap: [0; FL_COUNT],
+            heads: [[ptr::null_mut(); SL_COUNT]; FL_COUNT],
+            regions: Regions::new(),
+            usage: Usage::new(),
+        }
+    }
+
+    /// Adds the memory from `start` to `start + size` to the heap, as free
+    /// blocks followed by an end marker. Regions must not overlap each other,
+    /// touching ones stay apart thanks to the end marker.
+    ///
+    /// # Safety
+    /// This function is unsafe because the caller must guarantee that the given
+    /// memory is valid and unused.
+    pub unsafe fn add_region(&mut self, start: usize, size: usize) -> Result<(), RegionError> {
+        let end = (start + size) & !(ALIGN - 1);
+        let start = align_up(start, ALIGN);
+        if end < start || end - start < MIN_BLOCK + HEADER {
+            return Err(RegionError::TooSmall { start, end });
+        }
+        self.regions.add(start, end)?;
+        let blocks_end = end - HEADER;
+
+        let mut prev: *mut Block = ptr::null_mut();
+        let mut addr = start;
+        unsafe {
+            while blocks_end - addr >= MIN_BLOCK {
+                let block = addr as *mut Block;
+                let size = (blocks_end - addr).min(MAX_BLOCK);
+                (*block).prev_phys = prev;
+                (*block).size = size;
+                self.insert(block);
+                prev = block;
+                addr += size;
+            }
+
+            let end_marker = addr as *mut Block;
+            (*end_marker).prev_phys = prev;
+            (*end_marker).size = 0;
+        }
+        self.usage.size += addr - start;
+        Ok(())
+    }
+
+    /// Current usage of the heap.
+    pub fn stats(&self) -> HeapStats {
+        let mut largest_free = 0;
+        if self.fl_bitmap != 0 {
+            // the largest block is in the highest class that has any
+            let fl = (u32::BITS - 1 - self.fl_bitmap.leading_zeros()) as usize;
+            let sl = (u32::BITS - 1 - self.sl_bitmap[fl].leading_zeros()) as usize;
+            let mut block = self.heads[fl][sl];
+            while !block.is_null() {
+                unsafe {
+                    largest_free = largest_free.max((*block).size());
+                    block = (*block).next_free;
+                }
+            }
+        }
+        self.usage.stats(largest_free)
+    }
+
+    /// Walks the blocks of each region in address order and then the free
+    /// lists. Checks that every block lies inside a region, that the links
+    /// between neighbours and within the free lists agree, that every free
+    /// block is listed in its class and that the free blocks add up.
+    pub fn check(&self) -> Result<(), HeapError> {
+        let (mut free, mut free_blocks) = (0, 0);
+        for &(start, end) in self.regions.iter() {
+            let (bytes, blocks) = unsafe { Self::check_region(start, end)? };
+            free += bytes;
+            free_blocks += blocks;
+        }
+
+        unsafe {
+            // every free block seen above is on exactly one list, so walking
+            // more than that many means a list loops
+            let mut listed = 0;
+            for fl in 0..FL_COUNT {
+                for sl in 0..SL_COUNT {
+                    let head = self.heads[fl][sl];
+                    let has_blocks = self.sl_bitmap[fl] & (1 << sl) != 0;
+                    if head.is_null() == has_blocks {
+                        return Err(HeapError::BadLink(head as usize));
+                    }
+                    let mut prev: *mut Block = ptr::null_mut();
+                    let mut block = head;
+                    while !block.is_null() {
+                        let addr = block as usize;
+                        match self.regions.find(addr) {
+                            Some((_, end)) if addr < end - HEADER => {}
+                            _ => return Err(HeapError::OutOfBounds(addr)),
+                        }
+                        listed += 1;
+                        if listed > free_blocks
+                            || !(*block).is_free()
+                            || (*block).prev_free != prev
+                            || mapping((*block).size()) != (fl, sl)
+                        {
+                            return Err(HeapError::BadLink(addr));
+                        }
+                        prev = block;
+                        block = (*block).next_free;
+                    }
+                }
+                if (self.sl_bitmap[fl] != 0) != (self.fl_bitmap & (1 << fl) != 0) {
+                    return Err(HeapError::BadLink(0));
+                }
+            }
+            if listed != free_blocks {
+                return Err(HeapError::BadLink(0));
+            }
+        }
+        self.usage.check_free(free)
+    }
+
+    /// Walks the blocks of the region from `start` to `end`, returns the bytes
+    /// and the number of its free blocks.
+    unsafe fn check_region(start: usize, end: usize) -> Result<(usize, usize), HeapError> {
+        let (mut free, mut free_blocks) = (0, 0);
+        let mut prev: *mut Block = ptr::null_mut();
+        let mut block = start as *mut Block;
+        unsafe {
+            loop {
+                let addr = block as usize;
+                if addr > end - HEADER {
+                    return Err(HeapError::OutOfBounds(addr));
+                }
+                if (*block).prev_phys != prev {
+                    return Err(HeapError::BadLink(addr));
+                }
+                let size = (*block).size();
+                if size == 0 {
+                    // a tail too small for a block may follow the marker
+                    if end - HEADER - addr >= MIN_BLOCK {
+                        return Err(HeapError::BadSize(addr));
+                    }
+                    return Ok((free, free_blocks));
+                }
+                if size < MIN_BLOCK || !size.is_multiple_of(ALIGN) || size > end - HEADER - addr {
+                    return Err(HeapError::BadSize(addr));
+                }
+                if (*block).is_free() {
+                    if !prev.is_null() && (*prev).is_free() {
+                        return Err(HeapError::Unmerged(addr));
+                    }
+                    free += size;
+                    free_blocks += 1;
+                }
+                prev = block;
+                block = Block::next_phys(block);
+            }
+        }
+    }
+
+    /// Marks a block free and puts it at the head of its class.
+    unsafe fn insert(&mut self, block: *mut Block) {
+        let (fl, sl) = mapping(unsafe { (*block).size() });
+        let head = self.heads[fl][sl];
+        unsafe {
+            (*block).size |= FREE;
+            (*block).prev_free = ptr::null_mut();
+            (*block).next_free = head;
+            if !head.is_null() {
+                (*head).prev_free = block;
+            }
+        }
+        self.heads[fl][sl] = block;
+        self.sl_bitmap[fl] |= 1 << sl;
+        self.fl_bitmap |= 1 << fl;
+    }
+
+    /// Takes a free block out of its class and marks it used.
+    unsafe fn remove(&mut self, block: *mut Block) {
+        unsafe {
+            let (fl, sl) = mapping((*block).size());
+            let (prev, next) = ((*block).prev_free, (*block).next_free);
+            if !next.is_null() {
+                (*next).prev_free = prev;
+            }
+            if prev.is_null() {
+                self.heads[fl][sl] = next;
+                if next.is_null() {
+                    self.sl_bitmap[fl] &= !(1 << sl);
+                    if self.sl_bitmap[fl] == 0 {
+                        self.fl_bitmap &= !(1 << fl);
+                    }
+                }
+            } else {
+                (*prev).next_free = next;
+            }
+            (*block).size &= !FREE;
+        }
+    }
+
+    /// First free block of the class (fl, sl) or of the next larger class that
+    /// has one.
+    fn find(&self, fl: usize, sl: usize) -> Option<*mut Block> {
+        if fl >= FL_COUNT {
+            return None;
+        }
+        let mut fl = fl;
+        let mut sl_map = self.sl_bitmap[fl] & (!0u32 << sl);
+        if sl_map == 0 {
+            let fl_map = self.fl_bitmap & (!0u32).checked_shl(fl as u32 + 1).unwrap_or(0);
+            if fl_map == 0 {
+                return None;
+            }
+            fl = fl_map.trailing_zeros() as usize;
+            sl_map = self.sl_bitmap[fl];
+        }
+        Some(self.heads[fl][sl_map.trailing_zeros() as usize])
+    }
+
+    /// Cuts the first `size` bytes off a used block, the rest becomes a free
+    /// block.
+    unsafe fn split(&mut self, block: *mut Block, size: usize) {
+        unsafe {
+            let rest = (block as usize + size) as *mut Block;
+            (*rest).prev_phys = block;
+            (*rest).size = (*block).size() - size;
+            (*Block::next_phys(rest)).prev_phys = rest;
+            (*block).size = size;
+            self.insert(rest);
+        }
+    }
+
+    unsafe fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let payload = align_up(layout.size(), ALIGN).max(MIN_BLOCK - HEADER);
+        let size = match payload.checked_add(HEADER) {
+            Some(size) if size <= MAX_BLOCK => size,
+            _ => return ptr::null_mut(),
+        };
+        let align = layout.align();
+        // room to move the payload up to its alignment
+        let search = if align <= ALIGN { size } else { size + align + MIN_BLOCK };
+
+        let (fl, sl) = mapping_search(search);
+        let mut block = match self.find(fl, sl) {
+            Some(block) => block,
+            None => return ptr::null_mut(),
+        };
+        unsafe {
+            self.remove(block);
+
+            let start = Block::payload(block) as usize;
+            let mut aligned = align_up(start, align);
+            if aligned != start {
+                if aligned - start < MIN_BLOCK {
+                    aligned = align_up(start + MIN_BLOCK, align);
+                }
+                // the gap in front becomes a free block of its own
+                let front = block;
+                self.split(front, aligned - start);
+                block = Block::next_phys(front);
+                self.remove(block);
+                self.insert(front);
+            }
+
+            if (*block).size() >= size + MIN_BLOCK {
+                self.split(block, size);
+            }
+            self.usage.allocated((*block).size());
+            Block::payload(block)
+        }
+    }
+
+    unsafe fn free(&mut self, ptr: *mut u8) {
+        let mut block = Block::from_payload(ptr);
+        unsafe {
+            self.usage.freed((*block).size());
+            let next = Block::next_phys(block);
+            if (*next).is_free() {
+                self.remove(next);
+                (*block).size += (*next).size();
+                (*Block::next_phys(block)).prev_phys = block;
+            }
+
+            let prev = (*block).prev_phys;
+            if !prev.is_null() && (*prev).is_free() {
+                self.remove(prev);
+                (*prev).size += (*block).size();
+                (*Block::next_phys(prev)).prev_phys = prev;
+                block = prev;
+            }
+            self.insert(block);
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<TlsfAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        unsafe { self.lock().allocate(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        unsafe { self.lock().free(ptr) }
+    }
+}
@@ -0,0 +1,386 @@
+//! Memory protection for threads, enabled with the `mpu` feature.
+//!
+//! The MPU is reprogrammed on every context switch, so an unprivileged thread
+//! only reaches flash, the statics, its own stack, the heap blocks it
+//! allocated and the regions granted to it. Where regions overlap the higher
+//! number wins:
+//!
+//! | region | covers                                  | threads    |
+//! |--------|-----------------------------------------|------------|
+//! | 0      | code and read-only data in flash        | read, exec |
+//! | 1      | .data and .bss                          | read-write |
+//! | 2-4    | heap blocks of the thread and `grant`s  | as granted |
+//! | 5      | stack of the running thread             | read-write |
+//! | 6      | lowest `GUARD_SIZE` bytes of the stack  | none       |
+//! | 7      | counters in `.kernel.shared`            | read       |
+//!
+//! The kernel's statics are in the `.kernel` section after .bss. Apart from
+//! the counters threads read, no region covers it. The rest of the kernel's
+//! data, i.e. thread control blocks, list nodes, timer control blocks and
+//! allocation records, is on the heap next to the stacks, where a thread only
+//! reaches its own stack and blocks. Privileged code reaches all of it through
+//! the default memory map. A thread that runs into the guard or touches memory
+//! it was not given raises a MemManage fault and is terminated.
+//!
+//! Region 1 covers the statics exactly as long as they take at most 8 KiB,
+//! memory.x checks that at link time.
+
+use core::alloc::Layout;
+use core::arch::naked_asm;
+use cortex_m::asm;
+use cortex_m::peripheral::{MPU, SCB};
+use cortex_m_semihosting::hprintln;
+use crate::kernel::scheduler::{Scheduler, SCHEDULER};
+use crate::kernel::syscall::{is_privileged, Error};
+use crate::kernel::thread::{self, Tcb};
+
+/// Regions a thread can be granted, each heap block it allocates takes one.
+/// A thread holding this many blocks and grants can not allocate, its
+/// allocations return null until it frees a block.
+pub const GRANTS: usize = 3;
+
+/// Bytes at the bottom of every stack no thread may touch.
+pub const GUARD_SIZE: usize = 32;
+
+const FLASH_REGION: u32 = 0;
+const STATICS_REGION: u32 = 1;
+const GRANT_REGION: u32 = 2;
+const STACK_REGION: u32 = GRANT_REGION + GRANTS as u32;
+const GUARD_REGION: u32 = STACK_REGION + 1;
+const SHARED_REGION: u32 = GUARD_REGION + 1;
+const REGIONS: usize = SHARED_REGION as usize + 1;
+
+/// Smallest MPU region
+const MIN_REGION_SIZE: usize = 32;
+
+// MPU_CTRL
+const CTRL_ENABLE: u32 = 1 << 0;
+/// Privileged code may use the default memory map where no region applies
+const CTRL_PRIVDEFENA: u32 = 1 << 2;
+
+// MPU_RBAR
+const RBAR_VALID: u32 = 1 << 4;
+
+// MPU_RASR
+const RASR_ENABLE: u32 = 1 << 0;
+const RASR_XN: u32 = 1 << 28;
+/// Normal memory, write-through: flash
+const RASR_FLASH: u32 = 1 << 17;
+/// Normal memory, shareable, write-through: SRAM
+const RASR_RAM: u32 = 1 << 18 | 1 << 17;
+// Access permissions, bits 24-26
+const AP_MASK: u32 = 0b111 << 24;
+const AP_KERNEL_ONLY: u32 = 0b001 << 24;
+const AP_READ_ONLY: u32 = 0b010 << 24;
+const AP_READ_WRITE: u32 = 0b011 << 24;
+const AP_ALL_READ_ONLY: u32 = 0b110 << 24;
+
+// SCB_SHCSR
+const SHCSR_MEMFAULTENA: u32 = 1 << 16;
+
+// MMFSR, the low byte of SCB_CFSR
+const MMFSR_MSTKERR: u32 = 1 << 4;
+const MMFSR_MMARVALID: u32 = 1 << 7;
+
+/// What a thread may do in a granted region. Privileged code may always read
+/// and write, nobody may execute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    ReadOnly,
+    ReadWrite,
+}
+
+/// An MPU region, as written to the RBAR and RASR registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    rbar: u32,
+    rasr: u32,
+}
+
+impl Region {
+    /// Smallest region around the memory from `start` to `end`, `None` if it
+    /// is empty.
+    ///
+    /// MPU regions are a power of two in size and aligned to it, so the region
+    /// may reach past `start` and `end`. Regions of 256 bytes and more are cut
+    /// into eighths that are only enabled where they overlap the memory: a
+    /// block that is aligned to its size is covered exactly.
+    pub fn covering(start: usize, end: usize, access: Access) -> Option<Region> {
+        let ap = match access {
+            Access::ReadOnly => AP_READ_ONLY,
+            Access::ReadWrite => AP_READ_WRITE,
+        };
+        Self::build(start, end, ap | RASR_XN | RASR_RAM)
+    }
+
+    fn build(start: usize, end: usize, attributes: u32) -> Option<Region> {
+        if end <= start {
+            return None;
+        }
+        let mut size_log2 = 5;
+        let base = loop {
+            let size = 1usize.checked_shl(size_log2)?;
+            let base = start & !(size - 1);
+            if end - base <= size {
+                break base;
+            }
+            size_log2 += 1;
+        };
+        let size = 1 << size_log2;
+
+        // subregion disable bits for the eighths outside start..end
+        let mut disabled = 0;
+        if size >= 256 {
+            let eighth = size / 8;
+            for i in 0..8 {
+                let sub_start = base + i * eighth;
+                if sub_start + eighth <= start || sub_start >= end {
+                    disabled |= 1 << i;
+                }
+            }
+        }
+
+        Some(Region {
+            rbar: base as u32,
+            rasr: attributes | disabled << 8 | (size_log2 - 1) << 1 | RASR_ENABLE,
+        })
+    }
+
+    /// Parts of the region the MPU applies it to, i.e. its enabled
+    /// subregions, as start and end addresses.
+    fn parts(&self) -> impl Iterator<Item = (usize, usize)> {
+        let base = self.rbar as usize & !(MIN_REGION_SIZE - 1);
+        let size = 1usize << (((self.rasr >> 1) & 0x1F) + 1);
+        let (count, disabled) = if size >= 256 { (8, self.rasr >> 8 & 0xFF) } else { (1, 0) };
+        let part = size / count;
+        (0..count)
+            .filter(move |&i| disabled & 1 << i == 0)
+            .map(move |i| (base + i * part, base + (i + 1) * part))
+    }
+
+    /// True if an unprivileged access of this kind is allowed.
+    fn permits(&self, write: bool) -> bool {
+        match self.rasr & AP_MASK {
+            AP_READ_WRITE => true,
+            AP_READ_ONLY | AP_ALL_READ_ONLY => !write,
+            _ => false,
+        }
+    }
+}
+
+extern "C" {
+    // laid out by cortex-m-rt and memory.x
+    static __vector_table: u32;
+    static __erodata: u32;
+    static __sdata: u32;
+    static __skernel: u32;
+    static __ekernel_shared: u32;
+}
+
+fn mpu() -> &'static cortex_m::peripheral::mpu::RegisterBlock
+{
+    // Note(unsafe): the kernel is the only user of the MPU.
+    unsafe { &*MPU::PTR }
+}
+
+fn set_region(number: u32, region: Option<Region>)
+{
+    let mpu = mpu();
+    let region = region.unwrap_or(Region { rbar: 0, rasr: 0 });
+    // Note(unsafe): RBAR with the VALID bit selects the region itself.
+    unsafe {
+        mpu.rbar.write(region.rbar | RBAR_VALID | number);
+        mpu.rasr.write(region.rasr);
+    }
+}
+
+/// Regions all threads share, indexed by their number.
+fn shared_regions() -> [Option<Region>; REGIONS]
+{
+    let mut regions = [None; REGIONS];
+    let flash = (&raw const __vector_table as usize, &raw const __erodata as usize);
+    regions[FLASH_REGION as usize] = Region::build(flash.0, flash.1, AP_ALL_READ_ONLY | RASR_FLASH);
+    let statics = (&raw const __sdata as usize, &raw const __skernel as usize);
+    regions[STATICS_REGION as usize] = Region::covering(statics.0, statics.1, Access::ReadWrite);
+    let shared = (&raw const __skernel as usize, &raw const __ekernel_shared as usize);
+    regions[SHARED_REGION as usize] = Region::covering(shared.0, shared.1, Access::ReadOnly);
+    regions
+}
+
+/// Regions of `thread` while it runs, indexed by their number.
+fn regions(thread: &Tcb) -> [Option<Region>; REGIONS]
+{
+    let mut regions = shared_regions();
+    regions[GRANT_REGION as usize..STACK_REGION as usize].copy_from_slice(&thread.regions);
+    let stack = thread.stack as usize;
+    let attributes = RASR_XN | RASR_RAM;
+    regions[STACK_REGION as usize] = Region::build(stack, stack + thread.stack_size, AP_READ_WRITE | attributes);
+    regions[GUARD_REGION as usize] = Region::build(stack, stack + GUARD_SIZE, AP_KERNEL_ONLY | attributes);
+    regions
+}
+
+/// Sets up the regions shared by all threads and turns the MPU on. Must be
+/// called from `main`, before the scheduler is started.
+pub fn init()
+{
+    for (number, region) in shared_regions().iter().enumerate() {
+        set_region(number as u32, *region);
+    }
+
+    // Note(unsafe): enabling the fault handler and the MPU does not affect
+    // privileged code, which keeps the default memory map.
+    unsafe {
+        (*SCB::PTR).shcsr.modify(|shcsr| shcsr | SHCSR_MEMFAULTENA);
+        mpu().ctrl.write(CTRL_ENABLE | CTRL_PRIVDEFENA);
+    }
+    asm::dsb();
+    asm::isb();
+}
+
+/// Lets the thread with the given id access `region`, e.g. a peripheral or a
+/// buffer it shares with another thread. Fails with `Error::Full` once the
+/// thread has `GRANTS` regions, heap blocks it holds included, and with
+/// `Error::InvalidArgument` if it exited.
+///
+/// Walks the scheduler's lists in a critical section, so this is only
+/// available to privileged code, threads get `Error::NotPrivileged`.
+pub fn grant(id: usize, region: Region) -> Result<(), Error>
+{
+    if !is_privileged() {
+        return Err(Error::NotPrivileged);
+    }
+    cortex_m::interrupt::free(|_| {
+        let scheduler = unsafe { &mut *(&raw mut SCHEDULER as *mut Scheduler) };
+        let thread = scheduler.find(id).ok_or(Error::InvalidArgument)?;
+        let slot = thread.regions.iter_mut().find(|slot| slot.is_none()).ok_or(Error::Full)?;
+        *slot = Some(region);
+
+        if scheduler.current_thread.as_ref().is_some_and(|current| current.id == id) {
+            load(scheduler.current_thread.as_ref().unwrap());
+        }
+        Ok(())
+    })
+}
+
+/// Programs the regions of the thread about to run.
+pub(crate) fn load(thread: &Tcb)
+{
+    let regions = regions(thread);
+    for number in GRANT_REGION..=GUARD_REGION {
+        set_region(number, regions[number as usize]);
+    }
+    asm::dsb();
+    asm::isb();
+}
+
+/// True if `thread` may access the `size` bytes at `addr` itself, reading or
+/// writing. Services check the pointers a thread passes with this, they run
+/// privileged and would otherwise reach memory the thread can not.
+///
+/// Like the MPU, the region with the highest number that applies decides. It
+/// has to take the whole range, an access across regions is refused.
+pub(crate) fn accessible(thread: &Tcb, addr: usize, size: usize, write: bool) -> bool
+{
+    let end = match addr.checked_add(size.max(1)) {
+        Some(end) => end,
+        None => return false,
+    };
+    for region in regions(thread).iter().rev().flatten() {
+        let (mut overlap, mut inside) = (false, 0);
+        for (start, part_end) in region.parts() {
+            if start < end && addr < part_end {
+                overlap = true;
+                inside += part_end.min(end) - start.max(addr);
+            }
+        }
+        if overlap {
+            return inside == end - addr && region.permits(write);
+        }
+    }
+    false
+}
+
+/// Layout of a heap block a thread allocates: a power of two of at least the
+/// smallest region, aligned to its size, so one region covers it exactly.
+pub(crate) fn block_layout(layout: Layout) -> Option<Layout>
+{
+    let size = layout.size().max(layout.align()).max(MIN_REGION_SIZE).checked_next_power_of_two()?;
+    Layout::from_size_align(size, size).ok()
+}
+
+/// Grants the heap block of `size` bytes at `block` to `thread`, which is
+/// running and has a free region. See `block_layout`.
+pub(crate) fn grant_block(thread: &mut Tcb, block: usize, size: usize)
+{
+    if let Some(slot) = thread.regions.iter_mut().find(|slot| slot.is_none()) {
+        *slot = Region::covering(block, block + size, Access::ReadWrite);
+    }
+    load(thread);
+}
+
+/// Takes the heap block of `size` bytes at `block` back from `thread`, which
+/// is running and freed it.
+pub(crate) fn revoke_block(thread: &mut Tcb, block: usize, size: usize)
+{
+    let region = Region::covering(block, block + size, Access::ReadWrite);
+    if let Some(slot) = thread.regions.iter_mut().find(|slot| **slot == region) {
+        *slot = None;
+    }
+    load(thread);
+}
+
+// MemManage fault handler.
+//
+// Passes the exception return value to `mem_manage_handler`, it tells whether
+// the fault hit a thread or the kernel.
+#[no_mangle]
+#[unsafe(naked)]
+unsafe extern "C" fn MemoryManagement()
+{
+    naked_asm!(
+        "mov r0, lr",
+        "b mem_manage_handler",
+    );
+}
+
+/// Terminates the thread that caused the fault. A fault while stacking or in
+/// the guard is reported as stack overflow.
+#[no_mangle]
+extern "C" fn mem_manage_handler(exc_return: u32)
+{
+    // Note(unsafe): MMFSR is cleared by writing ones, MMFAR is only read.
+    let (status, address) = unsafe {
+        let scb = &*SCB::PTR;
+        let status = scb.cfsr.read() & 0xFF;
+        scb.cfsr.write(status);
+        (status, scb.mmfar.read() as usize)
+    };
+    if exc_return & 0b100 == 0 {
+        panic!("memory fault in the kernel, MMFSR {:#x}", status);
+    }
+
+    cortex_m::interrupt::free(|_| {
+        let scheduler = unsafe { &mut *(&raw mut SCHEDULER as *mut Scheduler) };
+        let current = match scheduler.current_thread.as_ref() {
+            Some(current) => current,
+            None => return,
+        };
+        let (id, stack, stack_top) = (current.id, current.stack as usize, current.stack as usize + current.stack_size);
+
+        let in_guard = status & MMFSR_MMARVALID != 0 && address >= stack && address < stack + GUARD_SIZE;
+        if status & MMFSR_MSTKERR != 0 || in_guard {
+            thread::stack_overflowed(id);
+        } else if status & MMFSR_MMARVALID != 0 {
+            hprintln!("thread {} accessed {:#x} without permission", id, address);
+        } else {
+            hprintln!("thread {} caused a memory fault, MMFSR {:#x}", id, status);
+        }
+
+        // The thread is never resumed. Its stack pointer may lie below the
+        // stack, the context switch must not save registers there.
+        // Note(unsafe): the top of the stack belongs to the thread.
+        unsafe { cortex_m::register::psp::write(stack_top as u32) };
+        scheduler.terminate_current();
+        SCB::set_pendsv();
+    });
+}
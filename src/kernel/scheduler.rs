@@ -1,27 +1,614 @@
-use alloc::collections::linked_list::LinkedList;
-use crate::kernel::thread::Tcb;
-use core::arch::{naked_asm, asm};
+use alloc::boxed::Box;
+use crate::list::{AllocError, LinkedList, Node};
+use crate::kernel::allocator::{free_all_recorded, free_kernel_node, kernel_node, ALLOCATOR};
+use crate::kernel::thread::{self, spawn, StackFrame, StackFrameExtension, State, Tcb};
+use crate::kernel::time;
+#[cfg(feature = "mpu")]
+use crate::kernel::mpu;
+use core::alloc::GlobalAlloc;
+use core::arch::{asm, naked_asm};
+use core::mem;
+use core::ptr;
+use cortex_m::peripheral::SCB;
+use cortex_m::asm as cm_asm;
+use cortex_m_semihosting::debug;
+use core::mem::MaybeUninit;
+
+/// Number of thread priorities, 0 is the lowest and `PRIORITY_LEVELS - 1` the
+/// highest. Limited by the width of the ready bitmap.
+pub const PRIORITY_LEVELS: usize = 32;
+
+/// Ticks a thread may run before it has to give way to the next one.
+pub const TIME_SLICE_TICKS: u32 = 10;
+
+/// The idle thread runs whenever no other thread is ready.
+pub const IDLE_PRIORITY: u8 = 0;
+
+const IDLE_STACK_SIZE: usize = 256;
+
+/// Ticks the idle thread was running, only written by the SysTick handler.
+/// Threads may read it, but not write it, see `mpu`.
+#[link_section = ".kernel.shared.idle_ticks"]
+static mut IDLE_TICKS: u64 = 0;
+
+/// Threads that did not exit yet, including the idle thread. Kept outside the
+/// scheduler as threads read it, see `thread_count`.
+#[link_section = ".kernel.shared.thread_count"]
+static mut THREAD_COUNT: usize = 0;
+
+#[link_section = ".kernel.scheduler"]
+pub static mut SCHEDULER: MaybeUninit<Scheduler> = MaybeUninit::uninit();
+
 pub struct Scheduler{
-    pub current_thread : Option<Tcb>,
-    pub threads : LinkedList<Tcb>,
-    pub id_counter : usize
+    pub current_thread : Option<Box<Node<Tcb>>>,
+    /// One FIFO of ready threads per priority
+    pub ready : [LinkedList<Tcb>; PRIORITY_LEVELS],
+    /// Bit n is set while `ready[n]` is not empty
+    ready_bitmap : u32,
+    /// Blocked threads, sorted by wake-up time
+    blocked : LinkedList<Tcb>,
+    /// Exited threads whose stacks were not freed yet
+    terminated : LinkedList<Tcb>,
+    idle_id : usize,
+    pub id_counter : usize,
+    /// Stamped into `Tcb::wait_seq` by `block_current`, orders waiters of
+    /// equal priority
+    wait_seq : u64,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Scheduler {
     pub const fn new() -> Self {
         Scheduler {
             current_thread: None,
-            threads : LinkedList::new(),
-            id_counter : 0
+            ready : [const { LinkedList::new() }; PRIORITY_LEVELS],
+            ready_bitmap : 0,
+            blocked : LinkedList::new(),
+            terminated : LinkedList::new(),
+            idle_id : 0,
+            id_counter : 0,
+            wait_seq : 0,
+        }
+    }
+
+    /// Allocates a node for a new thread and puts it into its ready queue. A
+    /// failure is reported for `requester`, the thread spawning it, if any.
+    pub fn add(&mut self, tcb: Tcb, requester: Option<usize>) -> Result<(), AllocError>
+    {
+        let node = kernel_node(requester, tcb)?;
+        self.push_ready(node);
+        unsafe { THREAD_COUNT += 1 };
+        Ok(())
+    }
+
+    /// Appends a thread to the ready queue of its priority.
+    pub(crate) fn push_ready(&mut self, node: Box<Node<Tcb>>)
+    {
+        let priority = node.priority as usize;
+        self.ready[priority].push_back(node);
+        self.ready_bitmap |= 1 << priority;
+    }
+
+    /// Takes the thread that waited longest out of the highest priority ready
+    /// queue.
+    pub(crate) fn pop_ready(&mut self) -> Option<Box<Node<Tcb>>>
+    {
+        let priority = self.highest_ready()? as usize;
+        let node = self.ready[priority].pop_front();
+        if self.ready[priority].is_empty() {
+            self.ready_bitmap &= !(1 << priority);
+        }
+        node
+    }
+
+    /// Priority of the most important ready thread.
+    pub(crate) fn highest_ready(&self) -> Option<u8>
+    {
+        if self.ready_bitmap == 0 {
+            return None;
+        }
+        Some(31 - self.ready_bitmap.leading_zeros() as u8)
+    }
+
+    /// Saves the stack pointer of the running thread and files it according to
+    /// its state: ready threads go to the end of their ready queue, so threads
+    /// of the same priority take turns.
+    ///
+    /// Nodes are only moved between lists, so this never touches the heap.
+    /// Returns false if the scheduler was not started yet.
+    fn park_current(&mut self, sp: *mut u32) -> bool
+    {
+        let mut current = match self.current_thread.take() {
+            Some(current) => current,
+            None => return false,
+        };
+        current.sp = sp;
+        if current.stack_overflowed() {
+            self.overflowed(&mut current);
+        }
+        match current.state {
+            State::TERMINATED => self.terminated.push_back(current),
+            // the wake-up time might have passed before we got here
+            State::BLOCKED if current.wake_at > time::now() => self.block(current),
+            State::BLOCKED => self.expire(current),
+            _ => {
+                current.state = State::READY;
+                self.push_ready(current);
+            }
+        }
+        true
+    }
+
+    /// Reports a thread that ran past the end of its stack and terminates it,
+    /// it can not continue with its stack and whatever lies below damaged.
+    fn overflowed(&mut self, thread: &mut Tcb)
+    {
+        thread::stack_overflowed(thread.id);
+        if thread.id == self.idle_id {
+            panic!("idle thread overflowed its stack");
+        }
+        if thread.state != State::TERMINATED {
+            thread.state = State::TERMINATED;
+            unsafe { THREAD_COUNT -= 1 };
+        }
+    }
+
+    /// Makes the highest priority ready thread the running one and returns its
+    /// stack pointer.
+    fn resume_next(&mut self) -> *mut u32
+    {
+        // the idle thread is always ready
+        let mut next = self.pop_ready().unwrap();
+        next.state = State::RUNNING;
+        next.time_slice = TIME_SLICE_TICKS;
+        let next_sp = next.sp;
+        if let Some(result) = next.wait_result.take() {
+            // Note(unsafe): a blocked thread was switched out by `PendSV`, its
+            // exception frame sits right above the saved r4-r11.
+            unsafe {
+                let frame = next_sp.add(mem::size_of::<StackFrameExtension>() / 4) as *mut StackFrame;
+                (*frame).r0 = result as u32;
+            }
+        }
+        #[cfg(feature = "mpu")]
+        mpu::load(&next);
+        self.current_thread = Some(next);
+
+        self.reap();
+        next_sp
+    }
+
+    /// Puts a thread into the blocked list, which is kept sorted by wake-up
+    /// time. Threads with the same wake-up time keep their order.
+    fn block(&mut self, node: Box<Node<Tcb>>)
+    {
+        self.blocked
+            .insert_when(node, |blocking, thread| blocking.wake_at < thread.wake_at);
+    }
+
+    /// Blocks the running thread on the kernel object at `key` until it is
+    /// woken by `wake_one`, `wake_each` or the tick counter reaching `wake_at`.
+    /// In the latter case the thread sees `timeout_result` in r0 and
+    /// `Tcb::wait_timeout` is called, if the object set it.
+    ///
+    /// The thread is moved to the blocked list on the next context switch,
+    /// until then it can be woken like any other blocked thread. The service
+    /// that blocked it still returns, but the thread never sees that value:
+    /// r0 is overwritten with `Tcb::wait_result` when it is switched back in.
+    pub(crate) fn block_current(&mut self, key: usize, wake_at: u64, timeout_result: usize)
+    {
+        if let Some(current) = self.current_thread.as_mut() {
+            self.wait_seq += 1;
+            current.state = State::BLOCKED;
+            current.wake_at = wake_at;
+            current.wait_on = key;
+            current.wait_seq = self.wait_seq;
+            current.wait_result = Some(timeout_result);
+        }
+    }
+
+    /// Blocks the running thread until the tick counter reaches `wake_at`.
+    pub(crate) fn sleep_current(&mut self, wake_at: u64)
+    {
+        self.block_current(0, wake_at, 0);
+    }
+
+    /// Moves every blocked thread whose wake-up time has come to the ready
+    /// queues.
+    pub(crate) fn wake_expired(&mut self, now: u64)
+    {
+        while self.blocked.front().is_some_and(|thread| thread.wake_at <= now) {
+            let node = self.blocked.pop_front().unwrap();
+            self.expire(node);
+        }
+    }
+
+    /// Readies a thread whose wait timed out. It keeps the timeout result set
+    /// by `block_current`.
+    fn expire(&mut self, mut node: Box<Node<Tcb>>)
+    {
+        let (key, timed_out) = (node.wait_on, node.wait_timeout);
+        Self::end_wait(&mut node);
+        node.state = State::READY;
+        self.push_ready(node);
+
+        if let Some(timed_out) = timed_out {
+            timed_out(self, key);
+        }
+    }
+
+    fn end_wait(thread: &mut Tcb)
+    {
+        thread.wait_on = 0;
+        thread.wait_data = 0;
+        thread.wait_mutex = ptr::null();
+        thread.wait_timeout = None;
+    }
+
+    /// Threads blocked on the kernel object at `key`, including the running
+    /// thread if it blocked but was not switched out yet.
+    pub(crate) fn waiters(&mut self, key: usize) -> impl Iterator<Item = &mut Tcb>
+    {
+        let current = self.current_thread.iter_mut().map(|node| &mut ***node);
+        current
+            .chain(self.blocked.iter_mut())
+            .filter(move |thread| thread.state == State::BLOCKED && thread.wait_on == key)
+    }
+
+    /// Id of the most important thread waiting on `key`, the one that waited
+    /// longest among equals.
+    pub(crate) fn first_waiter(&mut self, key: usize) -> Option<usize>
+    {
+        // the blocked list is sorted by wake-up time, not by when the threads
+        // blocked, `wait_seq` tells that
+        self.waiters(key)
+            .min_by_key(|thread| (u8::MAX - thread.priority, thread.wait_seq))
+            .map(|thread| thread.id)
+    }
+
+    /// Wakes the most important thread waiting on `key`. `wake` gets to
+    /// inspect the thread and returns the result it sees in r0.
+    ///
+    /// Returns false if nobody waits on `key`.
+    pub(crate) fn wake_one(&mut self, key: usize, wake: impl FnOnce(&mut Tcb) -> usize) -> bool
+    {
+        match self.first_waiter(key) {
+            Some(id) => self.wake_thread(id, wake),
+            None => false,
+        }
+    }
+
+    /// Wakes the blocked thread with the given id, see `wake_one`.
+    pub(crate) fn wake_thread(&mut self, id: usize, wake: impl FnOnce(&mut Tcb) -> usize) -> bool
+    {
+        if let Some(current) = self.current_thread.as_mut().filter(|current| current.id == id) {
+            if current.state != State::BLOCKED {
+                return false;
+            }
+            // blocked, but still on its way to the blocked list
+            let result = wake(current);
+            current.wait_result = Some(result);
+            current.state = State::RUNNING;
+            Self::end_wait(current);
+            return true;
+        }
+
+        let mut cursor = self.blocked.cursor_front_mut();
+        while cursor.inner().is_some_and(|thread| thread.id != id) {
+            cursor.move_next();
+        }
+        let mut node = match cursor.take() {
+            Some(node) => node,
+            None => return false,
+        };
+        let result = wake(&mut node);
+        node.wait_result = Some(result);
+        node.state = State::READY;
+        Self::end_wait(&mut node);
+        self.push_ready(node);
+        true
+    }
+
+    /// Offers every thread waiting on `key` to `wake`, in the order of the
+    /// blocked list. Threads for which it returns a result are woken and see
+    /// that result in r0.
+    pub(crate) fn wake_each(&mut self, key: usize, mut wake: impl FnMut(&mut Tcb) -> Option<usize>)
+    {
+        if let Some(current) = self.current_thread.as_mut() {
+            if current.state == State::BLOCKED && current.wait_on == key {
+                if let Some(result) = wake(current) {
+                    current.wait_result = Some(result);
+                    current.state = State::RUNNING;
+                    Self::end_wait(current);
+                }
+            }
+        }
+
+        let woken = LinkedList::new();
+        let mut cursor = self.blocked.cursor_front_mut();
+        while let Some(thread) = cursor.inner_mut() {
+            if thread.wait_on != key {
+                cursor.move_next();
+                continue;
+            }
+            match wake(thread) {
+                Some(result) => {
+                    thread.wait_result = Some(result);
+                    let node = cursor.take().unwrap();
+                    woken.push_back(node);
+                }
+                None => cursor.move_next(),
+            }
         }
+        while let Some(mut node) = woken.pop_front() {
+            node.state = State::READY;
+            Self::end_wait(&mut node);
+            self.push_ready(node);
+        }
+    }
+
+    /// Wakes every thread waiting on `key` with the same result.
+    pub(crate) fn wake_all(&mut self, key: usize, result: usize)
+    {
+        self.wake_each(key, |_| Some(result));
+    }
+
+    /// Changes the effective priority of a thread that did not exit yet, ready
+    /// threads move to the queue of their new priority.
+    pub(crate) fn set_priority(&mut self, id: usize, priority: u8)
+    {
+        if let Some(current) = self.current_thread.as_mut().filter(|current| current.id == id) {
+            current.priority = priority;
+            return;
+        }
+
+        for level in 0..PRIORITY_LEVELS {
+            let mut cursor = self.ready[level].cursor_front_mut();
+            while let Some(thread) = cursor.inner() {
+                if thread.id != id {
+                    cursor.move_next();
+                    continue;
+                }
+                let mut node = cursor.take().unwrap();
+                if self.ready[level].is_empty() {
+                    self.ready_bitmap &= !(1 << level);
+                }
+                node.priority = priority;
+                self.push_ready(node);
+                return;
+            }
+        }
+
+        if let Some(thread) = self.blocked.iter_mut().find(|thread| thread.id == id) {
+            thread.priority = priority;
+        }
+    }
+
+    /// Pends a context switch if a more important thread became ready.
+    pub(crate) fn reschedule(&self)
+    {
+        if self.preemption_due() {
+            SCB::set_pendsv();
+        }
+    }
+
+    /// Marks the running thread as exited, it is removed on the next context
+    /// switch.
+    pub(crate) fn terminate_current(&mut self)
+    {
+        if let Some(current) = self.current_thread.as_mut() {
+            current.state = State::TERMINATED;
+            unsafe { THREAD_COUNT -= 1 };
+        }
+    }
+
+    /// Returns stacks, control blocks and the heap blocks they did not free of
+    /// exited threads to the heap.
+    ///
+    /// The interrupted code might hold the allocator lock, in which case we
+    /// would spin forever. The threads then stay in the list until the next
+    /// context switch.
+    fn reap(&mut self)
+    {
+        if ALLOCATOR.is_locked() {
+            return;
+        }
+        while let Some(mut node) = self.terminated.pop_front() {
+            free_all_recorded(&mut node);
+            unsafe { ALLOCATOR.dealloc(node.stack, node.stack_layout()) };
+            free_kernel_node(node);
+        }
+    }
+
+    /// Accounts one tick to the running thread. Returns true if its time slice
+    /// ran out or a more important thread is ready, i.e. a context switch is
+    /// due.
+    pub(crate) fn tick(&mut self) -> bool
+    {
+        let current = match self.current_thread.as_mut() {
+            Some(current) => current,
+            None => return false,
+        };
+
+        current.cpu_ticks += 1;
+        if current.id == self.idle_id {
+            unsafe { IDLE_TICKS += 1 };
+        }
+
+        current.time_slice = current.time_slice.saturating_sub(1);
+        current.time_slice == 0 || self.preemption_due()
+    }
+
+    /// Every thread that did not exit yet.
+    pub(crate) fn threads(&mut self) -> impl Iterator<Item = &mut Tcb>
+    {
+        let current = self.current_thread.iter_mut().map(|node| &mut ***node);
+        let ready = self.ready.iter().flat_map(|list| list.iter_mut());
+        current.chain(ready).chain(self.blocked.iter_mut())
+    }
+
+    /// Looks up a thread that did not exit yet by its id.
+    pub(crate) fn find(&mut self, id: usize) -> Option<&mut Tcb>
+    {
+        self.threads().find(|thread| thread.id == id)
+    }
+
+    /// True if a ready thread has a higher priority than the running one.
+    pub(crate) fn preemption_due(&self) -> bool
+    {
+        match (self.current_thread.as_ref(), self.highest_ready()) {
+            (Some(current), Some(priority)) => priority > current.priority,
+            _ => false,
+        }
+    }
+}
+
+
+/// Kernel side of the context switch.
+///
+/// Called from `PendSV` with the process stack pointer of the outgoing thread
+/// (r4-r11 already pushed). Returns the stack pointer of the incoming thread in
+/// r0.
+#[no_mangle]
+extern "C" fn switch_context(sp: *mut u32) -> *mut u32
+{
+    cortex_m::interrupt::free(|_| {
+        let scheduler = unsafe { &mut *(&raw mut SCHEDULER as *mut Scheduler) };
+        if !scheduler.park_current(sp) {
+            // scheduler not started yet, stay on the current stack
+            return sp;
+        }
+        scheduler.resume_next()
+    })
+}
+
+/// Number of threads that did not exit yet, including the idle thread.
+pub fn thread_count() -> usize
+{
+    unsafe { ptr::read_volatile(&raw const THREAD_COUNT) }
+}
+
+/// Snapshot of the CPU time counters. The utilisation between two snapshots is
+/// given by `load_since`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuTime {
+    /// Ticks since the SysTick was started
+    pub total: u64,
+    /// Ticks spent in the idle thread
+    pub idle: u64,
+}
+
+impl CpuTime {
+    pub fn now() -> Self {
+        // Both counters are written by the SysTick, read until no tick landed
+        // in between.
+        loop {
+            let total = time::now();
+            let idle = unsafe { ptr::read_volatile(&raw const IDLE_TICKS) };
+            if time::now() == total {
+                return CpuTime { total, idle };
+            }
+        }
+    }
+
+    /// Percentage of time not spent idling since boot.
+    pub fn load_percent(&self) -> u32 {
+        Self::percent(self.total - self.idle, self.total)
     }
 
+    /// Percentage of time not spent idling since an `earlier` snapshot.
+    pub fn load_since(&self, earlier: &CpuTime) -> u32 {
+        let total = self.total - earlier.total;
+        let idle = self.idle - earlier.idle;
+        Self::percent(total - idle, total)
+    }
+
+    fn percent(busy: u64, total: u64) -> u32 {
+        if total == 0 {
+            return 0;
+        }
+        (busy * 100 / total) as u32
+    }
+}
+
+/// Spawns the idle thread and hands the CPU to the most important ready thread.
+///
+/// The code calling this is never resumed, the threads run on their own stacks
+/// from here on.
+pub fn start() -> !
+{
+    // Interrupts stay masked until the thread's stack and privilege level are
+    // in place, a PendSV in between would save the caller's context as the
+    // thread's.
+    cortex_m::interrupt::disable();
+
+    spawn(idle, ptr::null_mut(), IDLE_STACK_SIZE, IDLE_PRIORITY, "idle")
+        .expect("Failed to spawn the idle thread");
+
+    let scheduler = unsafe { &mut *(&raw mut SCHEDULER as *mut Scheduler) };
+    scheduler.idle_id = scheduler.id_counter;
+
+    let mut current = scheduler.pop_ready().unwrap();
+    current.state = State::RUNNING;
+    #[cfg(feature = "mpu")]
+    mpu::load(&current);
+    let stack_ptr = current.sp;
+    scheduler.current_thread = Some(current);
+
+    // Enter the thread the way an exception return would: take r4-r11 and
+    // the registers of the hardware frame, and leave the process stack pointer
+    // at the top of the frame, 8 byte aligned like the stack. Interrupts are
+    // enabled once the thread's stack is in use, but before privileges are
+    // dropped, `cpsie` does nothing in unprivileged code. A context switch in
+    // between saves and later resumes the thread right there.
+    unsafe {
+        asm!(
+        "ldmia r0!, {{r4-r11}}",
+        "ldr   r2, [r0]",           // argument
+        "ldr   lr, [r0, #20]",      // `thread_return`
+        "ldr   r1, [r0, #24]",      // entry function
+        "orr   r1, r1, #1",         // Thumb bit, cleared in the stacked pc
+        "adds  r0, #32",            // drop the frame, xpsr included
+        "msr   psp, r0",
+        "movs  r0, #2",             // on the process stack
+        "msr   control, r0",
+        "isb",
+        "cpsie i",
+        "movs  r0, #3",             // unprivileged
+        "msr   control, r0",
+        "isb",
+        "mov   r0, r2",
+        "bx    r1",
+        in("r0") stack_ptr as u32,
+        options(noreturn),
+        )
+    }
 }
 
+/// Runs at the lowest priority and sleeps until the next interrupt. Ends the
+/// program once every other thread exited.
+fn idle(_arg: *mut usize)
+{
+    loop {
+        if thread_count() == 1 {
+            debug::exit(debug::EXIT_SUCCESS);
+        }
+        cm_asm::wfi();
+    }
+}
 
+/// Context switch, pended by the kernel whenever another thread should run.
+///
+/// # Safety
+/// Only to be entered by the hardware as exception handler.
 #[no_mangle]
 #[unsafe(naked)]
-pub unsafe extern "C" fn PendSV() 
+pub unsafe extern "C" fn PendSV()
 {
          naked_asm!(
          "push    {{lr}}",
@@ -29,11 +616,11 @@ pub unsafe extern "C" fn PendSV()
          "stmdb   r0!, {{r4-r11}}", // push registers to stack A
          "bl      switch_context",  // call kernel for context switch
          "pop     {{lr}}",
-         "mov     r3, #2",
+         "mov     r3, #3",
         "msr     control, r3",      // run in unprivileged mode
          "isb",
          "ldmia   r0!, {{r4-r11}}",  // pop registers from stack B
          "msr     psp, r0",          // set process stack pointer
          "bx      lr"
          )
-}
\ No newline at end of file
+}
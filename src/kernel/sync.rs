@@ -0,0 +1,55 @@
+//! Blocking synchronisation primitives.
+//!
+//! Threads run unprivileged, so every operation that may block or wake a thread
+//! is a syscall. Waiting threads sit in the scheduler's blocked list, tagged
+//! with the address of the object they wait on.
+//!
+//! Exception handlers can not make syscalls, they use the `*_from_isr`
+//! variants, which never block and work on the object with interrupts
+//! disabled. A thread they wake preempts the interrupted one once the handler
+//! returns. Called from a thread, where interrupts can not be disabled, they
+//! make the syscall instead.
+
+pub mod barrier;
+pub mod condvar;
+pub mod event;
+pub mod mutex;
+pub mod notify;
+pub mod queue;
+pub mod rwlock;
+pub mod semaphore;
+#[cfg(feature = "self-test")]
+pub mod self_test;
+
+pub use crate::kernel::syscall::Error;
+pub use barrier::Barrier;
+pub use condvar::Condvar;
+pub use event::EventGroup;
+pub use mutex::{Mutex, MutexGuard, RecursiveMutex, RecursiveMutexGuard};
+pub use notify::{notify, notify_from_isr, notify_take, notify_wait, Notify};
+pub use queue::{Queue, SendError};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use semaphore::Semaphore;
+
+use crate::kernel::time;
+
+/// Ticks a blocking call may wait. `None` waits until the call succeeds,
+/// `Some(0)` fails with `Error::WouldBlock` instead of blocking.
+pub type Timeout = Option<u32>;
+
+/// Passes a timeout to the kernel in a single register.
+pub(crate) fn encode_timeout(timeout: Timeout) -> usize
+{
+    timeout.map_or(usize::MAX, |ticks| ticks as usize)
+}
+
+/// Tick at which a thread blocking with an encoded timeout gives up, `None` if
+/// it must not block at all.
+pub(crate) fn deadline(timeout: usize) -> Option<u64>
+{
+    match timeout {
+        0 => None,
+        usize::MAX => Some(u64::MAX),
+        ticks => Some(time::now() + ticks as u64),
+    }
+}
@@ -0,0 +1,57 @@
+use core::cell::Cell;
+use cortex_m::peripheral::SCB;
+use crate::kernel::scheduler::Scheduler;
+use crate::kernel::syscall::{decode, svc_call, Error, SysCall};
+
+/// Lets a group of `n` threads wait for each other: the first `n - 1` threads
+/// calling `wait` block until the last one arrives. The barrier is ready for
+/// the next round right away.
+pub struct Barrier {
+    /// Threads that arrived in the current round
+    arrived: Cell<usize>,
+    n: usize,
+}
+
+// Note(unsafe): the count is only touched by the kernel, which runs one
+// service at a time.
+unsafe impl Sync for Barrier {}
+
+impl Barrier {
+    /// Creates a barrier for `n` threads, a barrier for 0 threads behaves like
+    /// one for 1.
+    pub const fn new(n: usize) -> Self {
+        Barrier {
+            arrived: Cell::new(0),
+            n: if n == 0 { 1 } else { n },
+        }
+    }
+
+    /// Blocks until all threads of the group arrived. Returns true in exactly
+    /// one thread per round, the last one to arrive.
+    pub fn wait(&self) -> Result<bool, Error> {
+        decode(svc_call(SysCall::BARRIER_WAIT, self.key(), 0, 0)).map(|leader| leader == 1)
+    }
+
+    fn key(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Kernel side of `wait`.
+    pub(crate) fn arrive(&self, scheduler: &mut Scheduler) -> Result<usize, Error> {
+        let arrived = self.arrived.get() + 1;
+        if arrived == self.n {
+            self.arrived.set(0);
+            scheduler.wake_all(self.key(), 0);
+            scheduler.reschedule();
+            return Ok(1);
+        }
+
+        if scheduler.current_thread.is_none() {
+            return Err(Error::WouldBlock);
+        }
+        self.arrived.set(arrived);
+        scheduler.block_current(self.key(), u64::MAX, 0);
+        SCB::set_pendsv();
+        Ok(0)
+    }
+}
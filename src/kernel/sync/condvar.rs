@@ -0,0 +1,105 @@
+use cortex_m::peripheral::SCB;
+use crate::kernel::scheduler::Scheduler;
+use crate::kernel::sync::mutex::{MutexGuard, RawMutex};
+use crate::kernel::sync::{deadline, encode_timeout, Timeout};
+use crate::kernel::syscall::{decode, svc_call, Error, SysCall};
+
+/// Condition variable for threads holding a `Mutex`.
+///
+/// Waiting releases the mutex and blocks in the same syscall, so no notify can
+/// get lost in between. A notified thread is moved over to wait for the mutex
+/// and only runs again once it holds it, the way it would after a `lock`.
+///
+/// ```ignore
+/// let mut items = ITEMS.lock()?;
+/// while items.is_empty() {
+///     ITEMS_CHANGED.wait(&mut items, None)?;
+/// }
+/// ```
+pub struct Condvar {
+    /// Keeps the address unique, threads wait on it
+    _key: u8,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar { _key: 0 }
+    }
+
+    /// Unlocks the mutex of `guard` and blocks until notified, for at most
+    /// `timeout` ticks. Holds the mutex again when it returns, also with
+    /// `Error::Timeout`.
+    ///
+    /// Fails with `Error::WouldBlock`, without unlocking, if `timeout` is zero.
+    pub fn wait<T>(&self, guard: &mut MutexGuard<'_, T>, timeout: Timeout) -> Result<(), Error> {
+        let mutex = guard.raw();
+        let ret = svc_call(
+            SysCall::CONDVAR_WAIT,
+            self.key(),
+            mutex as *const RawMutex as usize,
+            encode_timeout(timeout),
+        );
+        match decode(ret) {
+            Ok(_) => Ok(()),
+            Err(Error::Timeout) => {
+                // timed out waiting for the notify or for the mutex after it
+                mutex.acquire(None)?;
+                Err(Error::Timeout)
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Wakes the most important waiting thread.
+    pub fn notify_one(&self) {
+        svc_call(SysCall::CONDVAR_NOTIFY, self.key(), 0, 0);
+    }
+
+    /// Wakes every waiting thread. They get the mutex one after the other.
+    pub fn notify_all(&self) {
+        svc_call(SysCall::CONDVAR_NOTIFY, self.key(), 1, 0);
+    }
+
+    fn key(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Kernel side of `wait`.
+    pub(crate) fn wait_on(&self, scheduler: &mut Scheduler, mutex: &RawMutex, timeout: usize) -> Result<usize, Error> {
+        let id = scheduler.current_thread.as_ref().map_or(0, |current| current.id);
+        if id == 0 || mutex.owner() != id {
+            return Err(Error::NotOwner);
+        }
+        let wake_at = deadline(timeout).ok_or(Error::WouldBlock)?;
+
+        mutex.unlock(scheduler)?;
+        scheduler.block_current(self.key(), wake_at, Error::Timeout.code());
+        if let Some(current) = scheduler.current_thread.as_mut() {
+            current.wait_data = mutex as *const RawMutex as usize;
+        }
+        SCB::set_pendsv();
+        Ok(0)
+    }
+
+    /// Kernel side of `notify_one` and `notify_all`.
+    pub(crate) fn notify(&self, scheduler: &mut Scheduler, all: bool) {
+        while let Some(id) = scheduler.first_waiter(self.key()) {
+            let mutex = match scheduler.find(id) {
+                Some(thread) => thread.wait_data as *const RawMutex,
+                None => break,
+            };
+            // Note(unsafe): the mutex outlives the threads waiting with it.
+            unsafe { (*mutex).lock_for(scheduler, id, self.key()) };
+            if !all {
+                break;
+            }
+        }
+        scheduler.reschedule();
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
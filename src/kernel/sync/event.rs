@@ -0,0 +1,174 @@
+use core::cell::Cell;
+use cortex_m::peripheral::SCB;
+use crate::kernel::scheduler::{Scheduler, SCHEDULER};
+use crate::kernel::sync::{deadline, encode_timeout, Timeout};
+use crate::kernel::syscall::{decode, is_privileged, svc_call, Error, SysCall};
+
+/// Describes a wait on an `EventGroup`. Lives on the stack of the waiting
+/// thread, which keeps its address in `Tcb::wait_data` while blocked.
+///
+/// The flags are handed back through the struct as a result in r0 could not be
+/// told apart from an error code.
+#[repr(C)]
+pub(crate) struct EventWait {
+    mask: u32,
+    /// Wait for all bits of `mask` instead of any
+    all: bool,
+    /// Clear the bits of `mask` once the wait is satisfied
+    clear: bool,
+    /// Encoded timeout
+    timeout: usize,
+    /// Flags at the moment the wait was satisfied
+    flags: u32,
+}
+
+impl EventWait {
+    fn satisfied_by(&self, flags: u32) -> bool {
+        if self.all {
+            flags & self.mask == self.mask
+        } else {
+            flags & self.mask != 0
+        }
+    }
+}
+
+/// 32 event flags threads can wait on, for any or all bits of a mask.
+///
+/// ```ignore
+/// const RX: u32 = 1 << 0;
+/// const TX: u32 = 1 << 1;
+/// static EVENTS: EventGroup = EventGroup::new();
+///
+/// // in an interrupt handler
+/// EVENTS.set_from_isr(RX);
+///
+/// // in a thread, wakes on RX or TX and clears whichever was set
+/// let flags = EVENTS.wait_any(RX | TX, true, Some(100))?;
+/// ```
+pub struct EventGroup {
+    flags: Cell<u32>,
+}
+
+// Note(unsafe): the flags are only modified by the kernel, in a service or with
+// interrupts disabled.
+unsafe impl Sync for EventGroup {}
+
+impl EventGroup {
+    pub const fn new() -> Self {
+        EventGroup { flags: Cell::new(0) }
+    }
+
+    /// Current flags.
+    pub fn get(&self) -> u32 {
+        self.flags.get()
+    }
+
+    /// Sets `bits` and wakes every thread whose wait is satisfied now.
+    pub fn set(&self, bits: u32) {
+        svc_call(SysCall::EVENT_SET, self.key(), bits as usize, 0);
+    }
+
+    /// `set` for exception handlers, see the `sync` module.
+    pub fn set_from_isr(&self, bits: u32) {
+        if !is_privileged() {
+            return self.set(bits);
+        }
+        cortex_m::interrupt::free(|_| {
+            let scheduler = unsafe { &mut *(&raw mut SCHEDULER as *mut Scheduler) };
+            self.update(scheduler, bits);
+        });
+    }
+
+    /// Clears `bits`.
+    pub fn clear(&self, bits: u32) {
+        svc_call(SysCall::EVENT_CLEAR, self.key(), bits as usize, 0);
+    }
+
+    /// Blocks at most `timeout` ticks until any bit of `mask` is set. Returns
+    /// the flags that satisfied the wait, before the bits of `mask` were
+    /// cleared if `clear` is set.
+    pub fn wait_any(&self, mask: u32, clear: bool, timeout: Timeout) -> Result<u32, Error> {
+        self.wait(mask, false, clear, timeout)
+    }
+
+    /// Like `wait_any`, but waits until all bits of `mask` are set.
+    pub fn wait_all(&self, mask: u32, clear: bool, timeout: Timeout) -> Result<u32, Error> {
+        self.wait(mask, true, clear, timeout)
+    }
+
+    fn wait(&self, mask: u32, all: bool, clear: bool, timeout: Timeout) -> Result<u32, Error> {
+        let mut wait = EventWait {
+            mask,
+            all,
+            clear,
+            timeout: encode_timeout(timeout),
+            flags: 0,
+        };
+        let ret = svc_call(SysCall::EVENT_WAIT, self.key(), &raw mut wait as usize, 0);
+        decode(ret)?;
+        Ok(wait.flags)
+    }
+
+    fn key(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Kernel side of `wait_any` and `wait_all`.
+    pub(crate) fn wait_for(&self, scheduler: &mut Scheduler, wait: &mut EventWait) -> Result<usize, Error> {
+        if wait.mask == 0 {
+            return Err(Error::InvalidArgument);
+        }
+        let flags = self.flags.get();
+        if wait.satisfied_by(flags) {
+            wait.flags = flags;
+            if wait.clear {
+                self.flags.set(flags & !wait.mask);
+            }
+            return Ok(0);
+        }
+
+        let wake_at = deadline(wait.timeout).ok_or(Error::WouldBlock)?;
+        if scheduler.current_thread.is_none() {
+            return Err(Error::WouldBlock);
+        }
+        scheduler.block_current(self.key(), wake_at, Error::Timeout.code());
+        if let Some(current) = scheduler.current_thread.as_mut() {
+            current.wait_data = wait as *mut EventWait as usize;
+        }
+        SCB::set_pendsv();
+        Ok(0)
+    }
+
+    /// Kernel side of `set` and `set_from_isr`.
+    pub(crate) fn update(&self, scheduler: &mut Scheduler, bits: u32) {
+        let flags = self.flags.get() | bits;
+
+        // every waiter sees the same flags, bits are cleared once all had a look
+        let mut clear = 0;
+        scheduler.wake_each(self.key(), |thread| {
+            // Note(unsafe): the wait lives on the stack of the blocked thread.
+            let wait = unsafe { &mut *(thread.wait_data as *mut EventWait) };
+            if !wait.satisfied_by(flags) {
+                return None;
+            }
+            wait.flags = flags;
+            if wait.clear {
+                clear |= wait.mask;
+            }
+            Some(0)
+        });
+        self.flags.set(flags & !clear);
+        scheduler.reschedule();
+    }
+
+    /// Kernel side of `clear`.
+    pub(crate) fn clear_bits(&self, bits: u32) {
+        self.flags.set(self.flags.get() & !bits);
+    }
+}
+
+impl Default for EventGroup {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,307 @@
+use core::cell::{Cell, UnsafeCell};
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use cortex_m::peripheral::SCB;
+use crate::kernel::scheduler::{self, Scheduler};
+use crate::kernel::sync::{deadline, encode_timeout, Timeout};
+use crate::kernel::syscall::{decode, svc_call, Error, SysCall};
+use crate::kernel::thread::State;
+
+/// Kernel part of a mutex: who owns it and how often it was locked.
+///
+/// A thread that finds the mutex locked blocks on it and lends its priority to
+/// the owner until it gets the mutex or gives up, so a medium priority thread
+/// cannot keep the owner from releasing it.
+pub struct RawMutex {
+    /// Id of the owning thread, 0 while unlocked
+    owner: Cell<usize>,
+    /// How often the owner locked it
+    count: Cell<usize>,
+    recursive: bool,
+}
+
+// Note(unsafe): the cells are only touched by the kernel, which runs one
+// service at a time.
+unsafe impl Sync for RawMutex {}
+
+impl RawMutex {
+    pub const fn new(recursive: bool) -> Self {
+        RawMutex {
+            owner: Cell::new(0),
+            count: Cell::new(0),
+            recursive,
+        }
+    }
+
+    /// Locks the mutex, blocking at most `timeout` ticks while another thread
+    /// owns it.
+    pub fn acquire(&self, timeout: Timeout) -> Result<(), Error> {
+        let ret = svc_call(SysCall::MUTEX_LOCK, self.key(), encode_timeout(timeout), 0);
+        decode(ret).map(|_| ())
+    }
+
+    /// Unlocks the mutex, it passes on to the most important waiting thread.
+    pub fn release(&self) -> Result<(), Error> {
+        decode(svc_call(SysCall::MUTEX_UNLOCK, self.key(), 0, 0)).map(|_| ())
+    }
+
+    /// Id of the owning thread, 0 while unlocked.
+    pub fn owner(&self) -> usize {
+        self.owner.get()
+    }
+
+    fn key(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Kernel side of `acquire`.
+    pub(crate) fn lock(&self, scheduler: &mut Scheduler, timeout: usize) -> Result<usize, Error> {
+        let current = scheduler.current_thread.as_mut().ok_or(Error::WouldBlock)?;
+        let id = current.id;
+
+        match self.owner.get() {
+            0 => {
+                self.owner.set(id);
+                self.count.set(1);
+                Ok(0)
+            }
+            owner if owner == id => {
+                if !self.recursive {
+                    return Err(Error::Deadlock);
+                }
+                self.count.set(self.count.get() + 1);
+                Ok(0)
+            }
+            owner => {
+                let wake_at = deadline(timeout).ok_or(Error::WouldBlock)?;
+                current.wait_mutex = self;
+                current.wait_timeout = Some(waiter_timed_out);
+                scheduler.block_current(self.key(), wake_at, Error::Timeout.code());
+                refresh_priority(scheduler, owner);
+                SCB::set_pendsv();
+                Ok(0)
+            }
+        }
+    }
+
+    /// Kernel side of `release`.
+    pub(crate) fn unlock(&self, scheduler: &mut Scheduler) -> Result<usize, Error> {
+        let id = scheduler.current_thread.as_ref().map_or(0, |current| current.id);
+        if id == 0 || self.owner.get() != id {
+            return Err(Error::NotOwner);
+        }
+
+        self.count.set(self.count.get() - 1);
+        if self.count.get() > 0 {
+            return Ok(0);
+        }
+
+        let mut next_owner = 0;
+        scheduler.wake_one(self.key(), |thread| {
+            next_owner = thread.id;
+            0
+        });
+        self.owner.set(next_owner);
+        if next_owner != 0 {
+            self.count.set(1);
+            // the new owner inherits from the threads still waiting
+            refresh_priority(scheduler, next_owner);
+        }
+        // give back what was inherited through this mutex
+        refresh_priority(scheduler, id);
+        scheduler.reschedule();
+        Ok(0)
+    }
+
+    /// Lets the thread `id`, blocked on the object at `key`, compete for the
+    /// mutex as if it had called `lock` without a timeout. It is woken right
+    /// away if the mutex is free and keeps waiting for the mutex otherwise.
+    pub(crate) fn lock_for(&self, scheduler: &mut Scheduler, id: usize, key: usize) {
+        if self.owner.get() == 0 {
+            self.owner.set(id);
+            self.count.set(1);
+            scheduler.wake_thread(id, |_| 0);
+            return;
+        }
+
+        let owner = self.owner.get();
+        if let Some(thread) = scheduler.find(id).filter(|thread| thread.wait_on == key) {
+            thread.wait_on = self.key();
+            thread.wait_mutex = self;
+            thread.wait_timeout = Some(waiter_timed_out);
+        }
+        refresh_priority(scheduler, owner);
+    }
+}
+
+/// Called by the scheduler when a thread waiting for the mutex at `key` timed
+/// out, the owner may no longer need the priority it inherited from it.
+fn waiter_timed_out(scheduler: &mut Scheduler, key: usize)
+{
+    // Note(unsafe): a mutex outlives the threads blocked on it.
+    let owner = unsafe { (*(key as *const RawMutex)).owner() };
+    refresh_priority(scheduler, owner);
+}
+
+/// Recomputes the priority of thread `id` as the highest of its base priority
+/// and the priorities of the threads waiting for mutexes it owns. A change is
+/// passed on to the owner of the mutex `id` waits for itself, and so on.
+fn refresh_priority(scheduler: &mut Scheduler, mut id: usize)
+{
+    // a deadlock makes the chain circular, it can not be longer than this
+    for _ in 0..scheduler::thread_count() {
+        let inherited = scheduler
+            .threads()
+            .filter(|thread| thread.state == State::BLOCKED && !thread.wait_mutex.is_null())
+            .filter(|thread| unsafe { (*thread.wait_mutex).owner() } == id)
+            .map(|thread| thread.priority)
+            .max();
+
+        let thread = match scheduler.find(id) {
+            Some(thread) => thread,
+            None => return,
+        };
+        let priority = inherited.map_or(thread.base_priority, |inherited| {
+            inherited.max(thread.base_priority)
+        });
+        if priority == thread.priority {
+            return;
+        }
+        let waits_for = thread.wait_mutex;
+        scheduler.set_priority(id, priority);
+
+        if waits_for.is_null() {
+            return;
+        }
+        id = unsafe { (*waits_for).owner() };
+    }
+}
+
+/// Mutual exclusion with priority inheritance. Waiting threads block in the
+/// scheduler instead of spinning.
+///
+/// Only usable from threads, interrupt handlers can not block.
+pub struct Mutex<T> {
+    raw: RawMutex,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Mutex {
+            raw: RawMutex::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Blocks until the mutex is ours. Fails with `Error::Deadlock` if the
+    /// calling thread already holds it.
+    pub fn lock(&self) -> Result<MutexGuard<'_, T>, Error> {
+        self.lock_timeout(None)
+    }
+
+    /// Takes the mutex if it is free, fails with `Error::WouldBlock` otherwise.
+    pub fn try_lock(&self) -> Result<MutexGuard<'_, T>, Error> {
+        self.lock_timeout(Some(0))
+    }
+
+    pub fn lock_timeout(&self, timeout: Timeout) -> Result<MutexGuard<'_, T>, Error> {
+        self.raw.acquire(timeout)?;
+        Ok(MutexGuard {
+            mutex: self,
+            _not_send: PhantomData,
+        })
+    }
+}
+
+/// Access to the data of a locked `Mutex`, unlocks it when dropped.
+pub struct MutexGuard<'a, T> {
+    pub(crate) mutex: &'a Mutex<T>,
+    /// The kernel only accepts the unlock from the thread that locked it
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> MutexGuard<'_, T> {
+    pub(crate) fn raw(&self) -> &RawMutex {
+        &self.mutex.raw
+    }
+}
+
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // can not fail, the guard proves we own the mutex
+        let _ = self.mutex.raw.release();
+    }
+}
+
+/// A mutex the owning thread may lock again. Every guard hands out shared
+/// access only, wrap the data in a `Cell` or `RefCell` to modify it.
+pub struct RecursiveMutex<T> {
+    raw: RawMutex,
+    data: T,
+}
+
+unsafe impl<T: Send> Sync for RecursiveMutex<T> {}
+
+impl<T> RecursiveMutex<T> {
+    pub const fn new(data: T) -> Self {
+        RecursiveMutex {
+            raw: RawMutex::new(true),
+            data,
+        }
+    }
+
+    pub fn lock(&self) -> Result<RecursiveMutexGuard<'_, T>, Error> {
+        self.lock_timeout(None)
+    }
+
+    pub fn try_lock(&self) -> Result<RecursiveMutexGuard<'_, T>, Error> {
+        self.lock_timeout(Some(0))
+    }
+
+    pub fn lock_timeout(&self, timeout: Timeout) -> Result<RecursiveMutexGuard<'_, T>, Error> {
+        self.raw.acquire(timeout)?;
+        Ok(RecursiveMutexGuard {
+            mutex: self,
+            _not_send: PhantomData,
+        })
+    }
+}
+
+/// Shared access to the data of a locked `RecursiveMutex`, unlocks it once when
+/// dropped.
+pub struct RecursiveMutexGuard<'a, T> {
+    mutex: &'a RecursiveMutex<T>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> Deref for RecursiveMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.mutex.data
+    }
+}
+
+impl<T> Drop for RecursiveMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        let _ = self.mutex.raw.release();
+    }
+}
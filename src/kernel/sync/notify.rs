@@ -0,0 +1,186 @@
+//! Direct-to-thread notifications.
+//!
+//! Every thread has a 32 bit notification word other threads and interrupt
+//! handlers update by thread id, without a kernel object in between. The thread
+//! waits for it either like for an event group (`notify_wait`) or like for a
+//! counting semaphore (`notify_take`).
+//!
+//! ```ignore
+//! // in the interrupt handler, `DRIVER` holds the id returned by `spawn`
+//! let _ = notify_from_isr(DRIVER, Notify::Increment);
+//!
+//! // in the driver thread
+//! let pending = notify_take(true, None)?;
+//! ```
+
+use cortex_m::peripheral::SCB;
+use crate::kernel::scheduler::{Scheduler, SCHEDULER};
+use crate::kernel::sync::{deadline, encode_timeout, Timeout};
+use crate::kernel::syscall::{decode, is_privileged, svc_call, Error, SysCall};
+use crate::kernel::thread::{State, Tcb};
+
+/// How a notification changes the word of the receiving thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Notify {
+    /// Or the bits into the word
+    SetBits(u32),
+    /// Add one to the word
+    Increment,
+    /// Replace the word
+    Overwrite(u32),
+}
+
+impl Notify {
+    fn encode(self) -> (usize, usize) {
+        match self {
+            Notify::SetBits(bits) => (0, bits as usize),
+            Notify::Increment => (1, 0),
+            Notify::Overwrite(value) => (2, value as usize),
+        }
+    }
+
+    fn decode(kind: usize, value: usize) -> Option<Notify> {
+        match kind {
+            0 => Some(Notify::SetBits(value as u32)),
+            1 => Some(Notify::Increment),
+            2 => Some(Notify::Overwrite(value as u32)),
+            _ => None,
+        }
+    }
+
+    fn apply(self, word: u32) -> u32 {
+        match self {
+            Notify::SetBits(bits) => word | bits,
+            Notify::Increment => word.wrapping_add(1),
+            Notify::Overwrite(value) => value,
+        }
+    }
+}
+
+/// Describes a wait for a notification. Lives on the stack of the waiting
+/// thread, which keeps its address in `Tcb::wait_data` while blocked.
+#[repr(C)]
+pub(crate) struct NotifyWait {
+    /// Wait for a non-zero word instead of any notification
+    counting: bool,
+    /// Counting: clear the word instead of decrementing it. Otherwise the bits
+    /// to clear.
+    clear: u32,
+    /// Encoded timeout
+    timeout: usize,
+    /// Word at the moment the wait was satisfied
+    value: u32,
+}
+
+impl NotifyWait {
+    /// Completes the wait if `thread` received what it waits for.
+    fn complete(&mut self, thread: &mut Tcb) -> bool {
+        if self.counting {
+            if thread.notify_value == 0 {
+                return false;
+            }
+            self.value = thread.notify_value;
+            thread.notify_value = if self.clear != 0 { 0 } else { self.value - 1 };
+        } else {
+            if !thread.notify_pending {
+                return false;
+            }
+            self.value = thread.notify_value;
+            thread.notify_value &= !self.clear;
+        }
+        thread.notify_pending = false;
+        true
+    }
+}
+
+/// Notifies the thread with the given id. Fails with
+/// `Error::InvalidArgument` if it exited.
+pub fn notify(id: usize, action: Notify) -> Result<(), Error> {
+    let (kind, value) = action.encode();
+    decode(svc_call(SysCall::NOTIFY, id, kind, value)).map(|_| ())
+}
+
+/// `notify` for exception handlers, see the `sync` module.
+pub fn notify_from_isr(id: usize, action: Notify) -> Result<(), Error> {
+    if !is_privileged() {
+        return notify(id, action);
+    }
+    cortex_m::interrupt::free(|_| {
+        let scheduler = unsafe { &mut *(&raw mut SCHEDULER as *mut Scheduler) };
+        send(scheduler, id, action).map(|_| ())
+    })
+}
+
+/// Blocks at most `timeout` ticks until the calling thread is notified.
+/// Returns the notification word, then clears its bits in `clear`.
+pub fn notify_wait(clear: u32, timeout: Timeout) -> Result<u32, Error> {
+    wait(false, clear, timeout)
+}
+
+/// Blocks at most `timeout` ticks until the notification word of the calling
+/// thread is not zero, i.e. uses it as a counting semaphore. Returns the word,
+/// then clears it if `clear` is set or decrements it otherwise.
+pub fn notify_take(clear: bool, timeout: Timeout) -> Result<u32, Error> {
+    wait(true, clear as u32, timeout)
+}
+
+fn wait(counting: bool, clear: u32, timeout: Timeout) -> Result<u32, Error> {
+    let mut wait = NotifyWait {
+        counting,
+        clear,
+        timeout: encode_timeout(timeout),
+        value: 0,
+    };
+    decode(svc_call(SysCall::NOTIFY_WAIT, &raw mut wait as usize, 0, 0))?;
+    Ok(wait.value)
+}
+
+/// Threads wait for notifications on the address of their own control block.
+fn key(thread: &Tcb) -> usize {
+    thread as *const Tcb as usize
+}
+
+/// Kernel side of `notify` and `notify_from_isr`.
+pub(crate) fn send(scheduler: &mut Scheduler, id: usize, action: Notify) -> Result<usize, Error> {
+    let thread = scheduler.find(id).ok_or(Error::InvalidArgument)?;
+    thread.notify_value = action.apply(thread.notify_value);
+    thread.notify_pending = true;
+
+    let key = key(thread);
+    if thread.state != State::BLOCKED || thread.wait_on != key {
+        return Ok(0);
+    }
+    // Note(unsafe): the wait lives on the stack of the blocked thread.
+    let wait = unsafe { &mut *(thread.wait_data as *mut NotifyWait) };
+    if wait.complete(thread) {
+        scheduler.wake_one(key, |_| 0);
+        scheduler.reschedule();
+    }
+    Ok(0)
+}
+
+/// Kernel side of `notify_wait` and `notify_take`.
+pub(crate) fn wait_for(scheduler: &mut Scheduler, wait: &mut NotifyWait) -> Result<usize, Error> {
+    let current = match scheduler.current_thread.as_mut() {
+        Some(current) => &mut ***current,
+        None => return Err(Error::WouldBlock),
+    };
+    if wait.complete(current) {
+        return Ok(0);
+    }
+
+    let wake_at = deadline(wait.timeout).ok_or(Error::WouldBlock)?;
+    let key = key(current);
+    scheduler.block_current(key, wake_at, Error::Timeout.code());
+    if let Some(current) = scheduler.current_thread.as_mut() {
+        current.wait_data = wait as *mut NotifyWait as usize;
+    }
+    SCB::set_pendsv();
+    Ok(0)
+}
+
+/// Kernel side of the `NOTIFY` service, checks the encoded action.
+pub(crate) fn send_encoded(scheduler: &mut Scheduler, id: usize, kind: usize, value: usize) -> Result<usize, Error> {
+    let action = Notify::decode(kind, value).ok_or(Error::InvalidArgument)?;
+    send(scheduler, id, action)
+}
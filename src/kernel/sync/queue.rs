@@ -0,0 +1,270 @@
+use core::cell::{Cell, UnsafeCell};
+use core::mem::{self, ManuallyDrop, MaybeUninit};
+use core::ptr;
+use cortex_m::peripheral::SCB;
+use crate::kernel::scheduler::{Scheduler, SCHEDULER};
+use crate::kernel::sync::{deadline, encode_timeout, Timeout};
+use crate::kernel::syscall::{decode, is_privileged, svc_call, Error, SysCall};
+
+/// Type erased part of a `Queue` the kernel works on. The item storage follows
+/// it in memory, `buf_offset` bytes from its start.
+///
+/// Receivers wait on the address of the queue, senders on the address after
+/// it. Blocked threads keep the address of their item or receive buffer in
+/// `Tcb::wait_data`, so an item is copied straight from a blocked sender or to
+/// a blocked receiver.
+#[repr(C)]
+pub struct RawQueue {
+    /// Slot of the oldest item
+    head: Cell<usize>,
+    len: Cell<usize>,
+    capacity: usize,
+    item_size: usize,
+    buf_offset: usize,
+}
+
+// Note(unsafe): the state is only touched by the kernel, in a service or with
+// interrupts disabled.
+unsafe impl Sync for RawQueue {}
+
+impl RawQueue {
+    const fn new(capacity: usize, item_size: usize, item_align: usize) -> Self {
+        RawQueue {
+            head: Cell::new(0),
+            len: Cell::new(0),
+            capacity,
+            item_size,
+            buf_offset: (mem::size_of::<RawQueue>() + item_align - 1) & !(item_align - 1),
+        }
+    }
+
+    fn receivers_key(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn senders_key(&self) -> usize {
+        // the queue is larger than a byte, no other object has this address
+        self.receivers_key() + 1
+    }
+
+    /// Bytes the queue takes with its storage, `None` if its state does not
+    /// add up. The queue lies in thread memory, services check it with this
+    /// before they touch the storage.
+    pub(crate) fn extent(&self) -> Option<usize> {
+        if self.head.get() >= self.capacity.max(1) || self.len.get() > self.capacity {
+            return None;
+        }
+        self.capacity.checked_mul(self.item_size)?.checked_add(self.buf_offset)
+    }
+
+    pub(crate) fn item_size(&self) -> usize {
+        self.item_size
+    }
+
+    fn slot(&self, index: usize) -> *mut u8 {
+        (self.receivers_key() + self.buf_offset + index * self.item_size) as *mut u8
+    }
+
+    fn copy(&self, from: usize, to: *mut u8) {
+        // Note(unsafe): items are only moved, never duplicated. The source is
+        // forgotten by the sender or freed as a slot.
+        unsafe { ptr::copy_nonoverlapping(from as *const u8, to, self.item_size) };
+    }
+
+    /// Kernel side of `Queue::send`, moves the item at `item` into the queue.
+    pub(crate) fn send(&self, scheduler: &mut Scheduler, item: usize, timeout: usize) -> Result<usize, Error> {
+        let handed_over = scheduler.wake_one(self.receivers_key(), |receiver| {
+            self.copy(item, receiver.wait_data as *mut u8);
+            0
+        });
+        if handed_over {
+            scheduler.reschedule();
+            return Ok(0);
+        }
+
+        let len = self.len.get();
+        if len < self.capacity {
+            self.copy(item, self.slot((self.head.get() + len) % self.capacity));
+            self.len.set(len + 1);
+            return Ok(0);
+        }
+
+        let wake_at = deadline(timeout).ok_or(Error::WouldBlock)?;
+        if scheduler.current_thread.is_none() {
+            return Err(Error::WouldBlock);
+        }
+        scheduler.block_current(self.senders_key(), wake_at, Error::Timeout.code());
+        if let Some(current) = scheduler.current_thread.as_mut() {
+            current.wait_data = item;
+        }
+        SCB::set_pendsv();
+        Ok(0)
+    }
+
+    /// Kernel side of `Queue::recv`, moves the oldest item to `out`.
+    pub(crate) fn recv(&self, scheduler: &mut Scheduler, out: usize, timeout: usize) -> Result<usize, Error> {
+        let len = self.len.get();
+        if len > 0 {
+            let head = self.head.get();
+            self.copy(self.slot(head) as usize, out as *mut u8);
+            self.head.set((head + 1) % self.capacity);
+            self.len.set(len - 1);
+
+            // the slot just freed goes to the most important blocked sender
+            let tail = self.slot((head + len) % self.capacity);
+            if scheduler.wake_one(self.senders_key(), |sender| {
+                self.copy(sender.wait_data, tail);
+                0
+            }) {
+                self.len.set(len);
+                scheduler.reschedule();
+            }
+            return Ok(0);
+        }
+
+        // only a queue without slots gets here with senders waiting
+        if scheduler.wake_one(self.senders_key(), |sender| {
+            self.copy(sender.wait_data, out as *mut u8);
+            0
+        }) {
+            scheduler.reschedule();
+            return Ok(0);
+        }
+
+        let wake_at = deadline(timeout).ok_or(Error::WouldBlock)?;
+        if scheduler.current_thread.is_none() {
+            return Err(Error::WouldBlock);
+        }
+        scheduler.block_current(self.receivers_key(), wake_at, Error::Timeout.code());
+        if let Some(current) = scheduler.current_thread.as_mut() {
+            current.wait_data = out;
+        }
+        SCB::set_pendsv();
+        Ok(0)
+    }
+}
+
+/// A `send` that failed, hands the item back.
+#[derive(Debug)]
+pub struct SendError<T> {
+    pub item: T,
+    /// `Error::WouldBlock` if the queue was full and the call must not block,
+    /// `Error::Timeout` if it stayed full
+    pub error: Error,
+}
+
+/// Bounded FIFO of `N` items of type `T`. A full queue blocks senders, an
+/// empty one receivers, both with a timeout.
+///
+/// Items are moved in and out by the kernel. A queue with `N == 0` hands each
+/// item directly from sender to receiver.
+#[repr(C)]
+pub struct Queue<T, const N: usize> {
+    raw: RawQueue,
+    buf: UnsafeCell<[MaybeUninit<T>; N]>,
+}
+
+unsafe impl<T: Send, const N: usize> Sync for Queue<T, N> {}
+
+impl<T, const N: usize> Queue<T, N> {
+    pub const fn new() -> Self {
+        Queue {
+            raw: RawQueue::new(N, mem::size_of::<T>(), mem::align_of::<T>()),
+            buf: UnsafeCell::new([const { MaybeUninit::uninit() }; N]),
+        }
+    }
+
+    /// Appends `item`, blocking at most `timeout` ticks while the queue is full.
+    pub fn send(&self, item: T, timeout: Timeout) -> Result<(), SendError<T>> {
+        let item = ManuallyDrop::new(item);
+        let ret = svc_call(
+            SysCall::QUEUE_SEND,
+            self.key(),
+            &*item as *const T as usize,
+            encode_timeout(timeout),
+        );
+        self.sent(item, decode(ret))
+    }
+
+    /// Appends `item` if there is room, fails with `Error::WouldBlock`
+    /// otherwise.
+    pub fn try_send(&self, item: T) -> Result<(), SendError<T>> {
+        self.send(item, Some(0))
+    }
+
+    /// `try_send` for exception handlers, see the `sync` module.
+    pub fn send_from_isr(&self, item: T) -> Result<(), SendError<T>> {
+        if !is_privileged() {
+            return self.try_send(item);
+        }
+        let item = ManuallyDrop::new(item);
+        let result = cortex_m::interrupt::free(|_| {
+            let scheduler = unsafe { &mut *(&raw mut SCHEDULER as *mut Scheduler) };
+            self.raw.send(scheduler, &*item as *const T as usize, 0)
+        });
+        self.sent(item, result)
+    }
+
+    /// Takes the oldest item, blocking at most `timeout` ticks while the queue
+    /// is empty.
+    pub fn recv(&self, timeout: Timeout) -> Result<T, Error> {
+        let mut item = MaybeUninit::<T>::uninit();
+        let ret = svc_call(
+            SysCall::QUEUE_RECV,
+            self.key(),
+            item.as_mut_ptr() as usize,
+            encode_timeout(timeout),
+        );
+        decode(ret)?;
+        // Note(unsafe): the kernel moved an item in.
+        Ok(unsafe { item.assume_init() })
+    }
+
+    /// Takes the oldest item if there is one, fails with `Error::WouldBlock`
+    /// otherwise.
+    pub fn try_recv(&self) -> Result<T, Error> {
+        self.recv(Some(0))
+    }
+
+    /// Items currently queued.
+    pub fn len(&self) -> usize {
+        self.raw.len.get()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    fn key(&self) -> usize {
+        &self.raw as *const RawQueue as usize
+    }
+
+    /// Gives the item back unless the kernel moved it into the queue.
+    fn sent(&self, item: ManuallyDrop<T>, result: Result<usize, Error>) -> Result<(), SendError<T>> {
+        match result {
+            Ok(_) => Ok(()),
+            Err(error) => Err(SendError { item: ManuallyDrop::into_inner(item), error }),
+        }
+    }
+}
+
+impl<T, const N: usize> Default for Queue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for Queue<T, N> {
+    fn drop(&mut self) {
+        let buf = self.buf.get_mut();
+        for i in 0..self.raw.len.get() {
+            let slot = (self.raw.head.get() + i) % N;
+            // Note(unsafe): slots between head and head + len hold items.
+            unsafe { buf[slot].assume_init_drop() };
+        }
+    }
+}
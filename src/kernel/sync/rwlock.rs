@@ -0,0 +1,274 @@
+use core::cell::{Cell, UnsafeCell};
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use cortex_m::peripheral::SCB;
+use crate::kernel::scheduler::Scheduler;
+use crate::kernel::sync::{deadline, encode_timeout, Timeout};
+use crate::kernel::syscall::{decode, svc_call, Error, SysCall};
+use crate::kernel::thread::Tcb;
+
+/// Read locks a thread may hold at once, further ones fail with `Error::Full`.
+pub const READ_LOCKS: usize = 4;
+
+/// Kernel part of a reader-writer lock.
+///
+/// Writers are preferred: once a writer waits, new readers wait too, so a
+/// steady stream of readers can not starve it. Readers wait on the address of
+/// the lock, writers on the address after it. Every reader notes the lock in
+/// its `Tcb`, so only a thread that holds it can give up a read lock.
+pub struct RawRwLock {
+    /// Threads holding the lock for reading
+    readers: Cell<usize>,
+    /// Id of the thread holding the lock for writing, 0 if none
+    writer: Cell<usize>,
+}
+
+// Note(unsafe): the cells are only touched by the kernel, which runs one
+// service at a time.
+unsafe impl Sync for RawRwLock {}
+
+impl RawRwLock {
+    pub const fn new() -> Self {
+        RawRwLock {
+            readers: Cell::new(0),
+            writer: Cell::new(0),
+        }
+    }
+
+    /// Locks for reading, blocking at most `timeout` ticks while a writer
+    /// holds the lock or waits for it. Fails with `Error::Full` if the thread
+    /// already holds `READ_LOCKS` read locks.
+    pub fn acquire_read(&self, timeout: Timeout) -> Result<(), Error> {
+        let ret = svc_call(SysCall::RWLOCK_READ, self.readers_key(), encode_timeout(timeout), 0);
+        decode(ret).map(|_| ())
+    }
+
+    /// Locks for writing, blocking at most `timeout` ticks while anyone else
+    /// holds the lock.
+    pub fn acquire_write(&self, timeout: Timeout) -> Result<(), Error> {
+        let ret = svc_call(SysCall::RWLOCK_WRITE, self.readers_key(), encode_timeout(timeout), 0);
+        decode(ret).map(|_| ())
+    }
+
+    /// Gives up a read or write lock of the calling thread.
+    pub fn release(&self) -> Result<(), Error> {
+        decode(svc_call(SysCall::RWLOCK_UNLOCK, self.readers_key(), 0, 0)).map(|_| ())
+    }
+
+    fn readers_key(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn writers_key(&self) -> usize {
+        // the lock is larger than a byte, no other object has this address
+        self.readers_key() + 1
+    }
+
+    /// Kernel side of `acquire_read`.
+    pub(crate) fn read(&self, scheduler: &mut Scheduler, timeout: usize) -> Result<usize, Error> {
+        let current = scheduler.current_thread.as_ref().ok_or(Error::WouldBlock)?;
+        // a free slot is needed to note the lock, now or once a writer is done
+        if !current.read_locks.contains(&0) {
+            return Err(Error::Full);
+        }
+        if self.writer.get() == 0 && scheduler.first_waiter(self.writers_key()).is_none() {
+            if let Some(current) = scheduler.current_thread.as_mut() {
+                self.add_reader(current);
+            }
+            return Ok(0);
+        }
+        self.block(scheduler, self.readers_key(), timeout)
+    }
+
+    /// Kernel side of `acquire_write`.
+    pub(crate) fn write(&self, scheduler: &mut Scheduler, timeout: usize) -> Result<usize, Error> {
+        let id = scheduler.current_thread.as_ref().ok_or(Error::WouldBlock)?.id;
+        match self.writer.get() {
+            0 if self.readers.get() == 0 => {
+                self.writer.set(id);
+                Ok(0)
+            }
+            writer if writer == id => Err(Error::Deadlock),
+            _ => self.block(scheduler, self.writers_key(), timeout),
+        }
+    }
+
+    fn block(&self, scheduler: &mut Scheduler, key: usize, timeout: usize) -> Result<usize, Error> {
+        let wake_at = deadline(timeout).ok_or(Error::WouldBlock)?;
+        let current = scheduler.current_thread.as_mut().ok_or(Error::WouldBlock)?;
+        if key == self.writers_key() {
+            current.wait_timeout = Some(writer_timed_out);
+        }
+        scheduler.block_current(key, wake_at, Error::Timeout.code());
+        SCB::set_pendsv();
+        Ok(0)
+    }
+
+    /// Kernel side of `release`. The lock is held for writing or reading by
+    /// the calling thread.
+    pub(crate) fn unlock(&self, scheduler: &mut Scheduler) -> Result<usize, Error> {
+        let current = scheduler.current_thread.as_mut().ok_or(Error::NotOwner)?;
+        if self.writer.get() == current.id {
+            self.writer.set(0);
+        } else {
+            let slot = current
+                .read_locks
+                .iter_mut()
+                .find(|lock| **lock == self.readers_key())
+                .ok_or(Error::NotOwner)?;
+            *slot = 0;
+            self.readers.set(self.readers.get() - 1);
+        }
+
+        // writers first, readers only wait while there are writers
+        if self.readers.get() == 0 && !self.hand_to_writer(scheduler) {
+            self.admit_readers(scheduler);
+        }
+        scheduler.reschedule();
+        Ok(0)
+    }
+
+    /// Passes the free lock to the most important waiting writer.
+    fn hand_to_writer(&self, scheduler: &mut Scheduler) -> bool {
+        let writer = &self.writer;
+        scheduler.wake_one(self.writers_key(), |thread| {
+            writer.set(thread.id);
+            0
+        })
+    }
+
+    /// Lets every waiting reader in, there is no writer holding or waiting.
+    fn admit_readers(&self, scheduler: &mut Scheduler) {
+        scheduler.wake_each(self.readers_key(), |thread| {
+            self.add_reader(thread);
+            Some(0)
+        });
+    }
+
+    /// Counts `thread` as a reader and notes the lock in a free slot, which
+    /// `read` made sure of.
+    fn add_reader(&self, thread: &mut Tcb) {
+        if let Some(slot) = thread.read_locks.iter_mut().find(|lock| **lock == 0) {
+            *slot = self.readers_key();
+            self.readers.set(self.readers.get() + 1);
+        }
+    }
+}
+
+impl Default for RawRwLock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Readers may have waited only for the writer that gave up.
+fn writer_timed_out(scheduler: &mut Scheduler, key: usize)
+{
+    // Note(unsafe): a lock outlives the threads blocked on it.
+    let lock = unsafe { &*((key - 1) as *const RawRwLock) };
+    if lock.writer.get() == 0 && scheduler.first_waiter(lock.writers_key()).is_none() {
+        lock.admit_readers(scheduler);
+        scheduler.reschedule();
+    }
+}
+
+/// Reader-writer lock, many threads may read at once, one may write. Waiting
+/// threads block in the scheduler.
+///
+/// Only usable from threads, interrupt handlers can not block.
+pub struct RwLock<T> {
+    raw: RawRwLock,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(data: T) -> Self {
+        RwLock {
+            raw: RawRwLock::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn read(&self) -> Result<RwLockReadGuard<'_, T>, Error> {
+        self.read_timeout(None)
+    }
+
+    pub fn try_read(&self) -> Result<RwLockReadGuard<'_, T>, Error> {
+        self.read_timeout(Some(0))
+    }
+
+    pub fn read_timeout(&self, timeout: Timeout) -> Result<RwLockReadGuard<'_, T>, Error> {
+        self.raw.acquire_read(timeout)?;
+        Ok(RwLockReadGuard {
+            lock: self,
+            _not_send: PhantomData,
+        })
+    }
+
+    /// Blocks until no one else holds the lock. Fails with `Error::Deadlock`
+    /// if the calling thread already writes.
+    pub fn write(&self) -> Result<RwLockWriteGuard<'_, T>, Error> {
+        self.write_timeout(None)
+    }
+
+    pub fn try_write(&self) -> Result<RwLockWriteGuard<'_, T>, Error> {
+        self.write_timeout(Some(0))
+    }
+
+    pub fn write_timeout(&self, timeout: Timeout) -> Result<RwLockWriteGuard<'_, T>, Error> {
+        self.raw.acquire_write(timeout)?;
+        Ok(RwLockWriteGuard {
+            lock: self,
+            _not_send: PhantomData,
+        })
+    }
+}
+
+/// Shared access to the data of a `RwLock`, unlocks it when dropped.
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        let _ = self.lock.raw.release();
+    }
+}
+
+/// Exclusive access to the data of a `RwLock`, unlocks it when dropped.
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    /// The kernel only accepts the unlock from the thread that locked it
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        let _ = self.lock.raw.release();
+    }
+}
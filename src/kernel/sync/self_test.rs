@@ -0,0 +1,396 @@
+//! Boot time test of the blocking primitives, built with the `self-test`
+//! feature. `start` spawns a controller and four helper threads, which run the
+//! tests once the scheduler is started and then exit, so the kernel exits too.
+//! A failed test halts with a panic.
+//!
+//! The controller has the highest priority, it only lets the helpers run by
+//! blocking and reads the order they got through from `LOG`. Once the helpers
+//! exited, the tests that need a second thread spawn a job for it, see
+//! `spawn_job`.
+
+use alloc::alloc::alloc;
+#[cfg(feature = "mpu")]
+use alloc::alloc::dealloc;
+use core::alloc::Layout;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::{mem, ptr};
+use cortex_m_semihosting::hprintln;
+#[cfg(feature = "mpu")]
+use crate::kernel::mpu;
+use crate::kernel::allocator::pool::Pool;
+use crate::kernel::allocator::set_alloc_failure_hook;
+#[cfg(feature = "mpu")]
+use crate::kernel::scheduler::SCHEDULER;
+use crate::kernel::sync::rwlock::{RawRwLock, READ_LOCKS};
+use crate::kernel::sync::{notify, notify_take, notify_wait, Notify};
+use crate::kernel::sync::{Condvar, Error, EventGroup, Mutex, Queue, RwLock, Semaphore};
+#[cfg(feature = "mpu")]
+use crate::kernel::thread::cpu_ticks;
+use crate::kernel::thread::{sleep_ticks, spawn, SpawnError};
+use crate::kernel::time;
+
+const STACK_SIZE: usize = 512;
+
+const CONTROLLER_PRIORITY: u8 = 5;
+
+/// Priority of the threads `spawn_job` spawns, below the controller.
+const JOB_PRIORITY: u8 = 4;
+
+/// Priorities of the helpers, indexed by the argument they are spawned with.
+/// The last two are equal to check the order among equals.
+const HELPERS: [u8; 4] = [2, 3, 4, 4];
+
+/// Timeout of the last helper's wait in `wakeup_order`, long enough to not
+/// expire. The helper before it waits without one, so it blocks first but
+/// wakes up last.
+const PERMIT_TIMEOUT: u32 = 1000;
+
+/// Ticks the low priority helper keeps `SHARED` locked while it is busy.
+const BUSY_TICKS: u64 = 10;
+
+/// Every helper waits for one permit in `wakeup_order`.
+static PERMITS: Semaphore = Semaphore::new(0, HELPERS.len());
+
+/// Starts the part of a helper in `priority_inheritance`.
+static GO: [Semaphore; 3] = [const { Semaphore::new(0, 1) }; 3];
+
+static SHARED: Mutex<()> = Mutex::new(());
+
+/// Helpers send their index once they got through, jobs what they saw.
+static LOG: Queue<usize, 4> = Queue::new();
+
+static RW: RawRwLock = RawRwLock::new();
+
+static EVENTS: EventGroup = EventGroup::new();
+
+/// Set by the controller, the job waits on `CHANGED` until it is not 0.
+static STATE: Mutex<usize> = Mutex::new(0);
+static CHANGED: Condvar = Condvar::new();
+
+static BLOCKS: Pool<usize, 1> = Pool::new();
+
+/// Id of the controller thread.
+static CONTROLLER: AtomicUsize = AtomicUsize::new(0);
+
+/// Allocation failures `count_alloc_failure` saw, and the thread of the last.
+static ALLOC_FAILURES: AtomicUsize = AtomicUsize::new(0);
+static FAILED_THREAD: AtomicUsize = AtomicUsize::new(0);
+
+const RX: u32 = 1 << 0;
+const TX: u32 = 1 << 1;
+const ERR: u32 = 1 << 2;
+
+/// One lock more than a thread may hold for reading.
+static READERS: [RwLock<()>; READ_LOCKS + 1] = [const { RwLock::new(()) }; READ_LOCKS + 1];
+
+pub fn start() -> Result<(), SpawnError>
+{
+    set_alloc_failure_hook(count_alloc_failure).expect("main is privileged");
+    let controller = spawn(controller, ptr::null_mut(), STACK_SIZE, CONTROLLER_PRIORITY, "self-test")?;
+    CONTROLLER.store(controller.id(), Ordering::Relaxed);
+    for (index, &priority) in HELPERS.iter().enumerate() {
+        spawn(helper, index as *mut usize, STACK_SIZE, priority, "helper")?;
+    }
+    Ok(())
+}
+
+fn controller(_arg: *mut usize)
+{
+    // the helpers block on PERMITS in the order they were spawned
+    sleep_ticks(1);
+
+    wakeup_order();
+    timeouts();
+    priority_inheritance();
+    // let the helpers exit, the jobs below take their place on the heap
+    sleep_ticks(1);
+
+    rwlock();
+    events();
+    notifications();
+    condvar();
+    pool();
+    alloc_failure();
+    #[cfg(feature = "mpu")]
+    heap_grants();
+    #[cfg(feature = "mpu")]
+    memory_fault();
+    hprintln!("sync self test passed");
+}
+
+/// A permit goes to the most important waiter, equals get it in the order they
+/// blocked, no matter how long they are willing to wait.
+fn wakeup_order()
+{
+    for expected in [2, 3, 1, 0] {
+        PERMITS.give().unwrap();
+        assert_eq!(LOG.recv(None), Ok(expected), "permit went to the wrong thread");
+    }
+}
+
+/// A wait gives up once its timeout passed, a zero timeout does not block.
+fn timeouts()
+{
+    let start = time::now();
+    assert_eq!(PERMITS.take(Some(5)), Err(Error::Timeout));
+    assert!(time::now() - start >= 5, "wait timed out early");
+    assert_eq!(LOG.recv(Some(2)), Err(Error::Timeout));
+
+    assert_eq!(PERMITS.try_take(), Err(Error::WouldBlock));
+    assert_eq!(LOG.try_recv(), Err(Error::WouldBlock));
+}
+
+/// While the high priority helper waits for `SHARED`, the low priority one
+/// holding it runs at high priority, so the medium one can not get in between.
+fn priority_inheritance()
+{
+    // low locks SHARED and stays busy
+    GO[0].give().unwrap();
+    sleep_ticks(1);
+    // high blocks on SHARED
+    GO[2].give().unwrap();
+    sleep_ticks(1);
+    // medium is ready while low still holds SHARED
+    GO[1].give().unwrap();
+
+    for expected in [0, 2, 1] {
+        assert_eq!(LOG.recv(None), Ok(expected), "priority inversion");
+    }
+}
+
+/// Every heap block takes one of the thread's regions, one allocation more
+/// than there are regions fails and leaves the others alone.
+/// The hook learns about a failed allocation and who asked for it.
+fn alloc_failure()
+{
+    // more than the whole heap
+    let layout = Layout::from_size_align(8 * 1024, 8).unwrap();
+    assert!(unsafe { alloc(layout) }.is_null(), "allocated more than the heap");
+    assert_eq!(ALLOC_FAILURES.load(Ordering::Relaxed), 1, "the hook was not called");
+    assert_eq!(FAILED_THREAD.load(Ordering::Relaxed), CONTROLLER.load(Ordering::Relaxed));
+}
+
+fn count_alloc_failure(thread: Option<usize>, _layout: Layout)
+{
+    ALLOC_FAILURES.fetch_add(1, Ordering::Relaxed);
+    FAILED_THREAD.store(thread.unwrap_or(0), Ordering::Relaxed);
+}
+
+#[cfg(feature = "mpu")]
+fn heap_grants()
+{
+    let layout = Layout::new::<[u32; 8]>();
+    let blocks = [(); mpu::GRANTS].map(|_| unsafe { alloc(layout) });
+    assert!(!blocks.contains(&ptr::null_mut()), "allocating a block failed");
+    assert!(unsafe { alloc(layout) }.is_null(), "allocated more blocks than regions");
+    // the heap is not exhausted, the hook is left alone
+    assert_eq!(ALLOC_FAILURES.load(Ordering::Relaxed), 1, "the hook was called");
+
+    for block in blocks {
+        // the thread still reaches its blocks
+        unsafe {
+            block.write(1);
+            dealloc(block, layout);
+        }
+    }
+    let block = unsafe { alloc(layout) };
+    assert!(!block.is_null(), "freeing a block kept its region");
+    unsafe { dealloc(block, layout) };
+}
+
+/// Only a reader gives up its read lock, a waiting writer keeps new readers
+/// out and a thread holds at most `READ_LOCKS` read locks.
+fn rwlock()
+{
+    RW.acquire_read(None).unwrap();
+    spawn_job(writer);
+    // the writer fails to give up the read lock and waits for the lock
+    sleep_ticks(1);
+    assert_eq!(RW.acquire_read(Some(0)), Err(Error::WouldBlock), "reader got ahead of a writer");
+    RW.release().unwrap();
+    assert_eq!(LOG.recv(None), Ok(0));
+
+    let guards: [_; READ_LOCKS] = core::array::from_fn(|i| READERS[i].read().unwrap());
+    assert_eq!(READERS[READ_LOCKS].try_read().err(), Some(Error::Full), "too many read locks");
+    drop(guards);
+    assert!(READERS[READ_LOCKS].try_read().is_ok(), "read locks were not given back");
+}
+
+fn writer()
+{
+    assert_eq!(RW.release(), Err(Error::NotOwner), "gave up another thread's read lock");
+    RW.acquire_write(None).unwrap();
+    RW.release().unwrap();
+    LOG.send(0, None).unwrap();
+}
+
+/// A wait for all bits sleeps through a part of them, and clears only the bits
+/// it waited for once it is satisfied.
+fn events()
+{
+    spawn_job(event_waiter);
+    sleep_ticks(1);
+    EVENTS.set(RX);
+    sleep_ticks(1);
+    assert_eq!(LOG.try_recv(), Err(Error::WouldBlock), "woken before all bits were set");
+
+    EVENTS.set(TX | ERR);
+    assert_eq!(LOG.recv(None), Ok((RX | TX | ERR) as usize));
+    assert_eq!(EVENTS.get(), ERR, "the bits waited for were not cleared");
+
+    // without clearing the bits stay set
+    assert_eq!(EVENTS.wait_any(ERR, false, Some(0)), Ok(ERR));
+    assert_eq!(EVENTS.get(), ERR);
+    EVENTS.clear(ERR);
+    assert_eq!(EVENTS.wait_any(ERR, false, Some(0)), Err(Error::WouldBlock));
+}
+
+fn event_waiter()
+{
+    let flags = EVENTS.wait_all(RX | TX, true, None).unwrap();
+    LOG.send(flags as usize, None).unwrap();
+}
+
+/// A notification wakes the thread with its word, a counting wait takes one
+/// increment or all of them.
+fn notifications()
+{
+    let id = spawn_job(notified);
+    sleep_ticks(1);
+    notify(id, Notify::SetBits(RX | ERR)).unwrap();
+    assert_eq!(LOG.recv(None), Ok((RX | ERR) as usize));
+
+    // the job only gets to take them once the controller blocks
+    for _ in 0..3 {
+        notify(id, Notify::Increment).unwrap();
+    }
+    for expected in [3, 2, 0] {
+        assert_eq!(LOG.recv(None), Ok(expected), "wrong notification word");
+    }
+    assert_eq!(notify(0, Notify::Increment), Err(Error::InvalidArgument), "notified a thread that does not exist");
+}
+
+fn notified()
+{
+    LOG.send(notify_wait(u32::MAX, None).unwrap() as usize, None).unwrap();
+    // the bits were cleared, the increments count from 0
+    LOG.send(notify_take(false, None).unwrap() as usize, None).unwrap();
+    LOG.send(notify_take(true, None).unwrap() as usize, None).unwrap();
+    assert_eq!(notify_take(false, Some(0)), Err(Error::WouldBlock), "the word was not cleared");
+    LOG.send(0, None).unwrap();
+}
+
+/// Waiting unlocks the mutex, a notified thread runs once it holds it again.
+fn condvar()
+{
+    spawn_job(state_waiter);
+    sleep_ticks(1);
+    {
+        // the waiting job gave the mutex up
+        let mut state = STATE.try_lock().expect("waiting kept the mutex locked");
+        *state = 7;
+        CHANGED.notify_one();
+        sleep_ticks(1);
+        assert_eq!(LOG.try_recv(), Err(Error::WouldBlock), "woken without the mutex");
+    }
+    assert_eq!(LOG.recv(None), Ok(7));
+
+    let mut state = STATE.lock().unwrap();
+    assert_eq!(CHANGED.wait(&mut state, Some(2)), Err(Error::Timeout));
+    // the mutex is held again after the timeout
+    *state = 0;
+}
+
+fn state_waiter()
+{
+    let mut state = STATE.lock().unwrap();
+    while *state == 0 {
+        CHANGED.wait(&mut state, None).unwrap();
+    }
+    LOG.send(*state, None).unwrap();
+}
+
+/// An empty pool blocks, a returned block goes straight to the waiting thread.
+fn pool()
+{
+    let block = BLOCKS.try_alloc(1).unwrap();
+    assert_eq!(BLOCKS.try_alloc(2).err().map(|e| e.error), Some(Error::WouldBlock));
+    assert_eq!(BLOCKS.alloc(2, Some(2)).err().map(|e| e.error), Some(Error::Timeout));
+
+    spawn_job(pool_waiter);
+    sleep_ticks(1);
+    drop(block);
+    assert_eq!(BLOCKS.available(), 0, "the block was not handed to the waiting thread");
+    assert_eq!(LOG.recv(None), Ok(5));
+    sleep_ticks(1);
+    assert_eq!(BLOCKS.available(), 1, "the block was not returned");
+}
+
+fn pool_waiter()
+{
+    let block = BLOCKS.alloc(5, None).unwrap();
+    LOG.send(*block, None).unwrap();
+}
+
+/// A thread touching kernel memory takes a MemManage fault and is terminated,
+/// the others carry on. The fault handler prints the address.
+#[cfg(feature = "mpu")]
+fn memory_fault()
+{
+    let id = spawn_job(touch_kernel);
+    sleep_ticks(1);
+    assert_eq!(LOG.try_recv(), Err(Error::WouldBlock), "a thread read kernel memory");
+    assert_eq!(cpu_ticks(id), Err(Error::InvalidArgument), "the faulting thread was not terminated");
+}
+
+#[cfg(feature = "mpu")]
+fn touch_kernel()
+{
+    // Note(unsafe): only reads, and faults before that.
+    let word = unsafe { (&raw const SCHEDULER as *const usize).read_volatile() };
+    LOG.send(word, None).unwrap();
+}
+
+fn helper(arg: *mut usize)
+{
+    let index = arg as usize;
+    let timeout = if index == HELPERS.len() - 1 { Some(PERMIT_TIMEOUT) } else { None };
+    PERMITS.take(timeout).unwrap();
+    LOG.send(index, None).unwrap();
+
+    match index {
+        0 => {
+            GO[0].take(None).unwrap();
+            let _shared = SHARED.lock().unwrap();
+            let until = time::now() + BUSY_TICKS;
+            while time::now() < until {}
+            LOG.send(0, None).unwrap();
+        }
+        1 => {
+            GO[1].take(None).unwrap();
+            LOG.send(1, None).unwrap();
+        }
+        2 => {
+            GO[2].take(None).unwrap();
+            let _shared = SHARED.lock().unwrap();
+            LOG.send(2, None).unwrap();
+        }
+        _ => {}
+    }
+}
+
+/// Runs `job` in a thread of its own, at `JOB_PRIORITY`. Returns the id of the
+/// thread.
+fn spawn_job(job: fn()) -> usize
+{
+    spawn(run_job, job as *mut usize, STACK_SIZE, JOB_PRIORITY, "job")
+        .expect("Failed to spawn a job")
+        .id()
+}
+
+fn run_job(arg: *mut usize)
+{
+    // Note(unsafe): `spawn_job` passes a `fn()`.
+    let job = unsafe { mem::transmute::<*mut usize, fn()>(arg) };
+    job();
+}
@@ -0,0 +1,104 @@
+use core::cell::Cell;
+use cortex_m::peripheral::SCB;
+use crate::kernel::scheduler::{Scheduler, SCHEDULER};
+use crate::kernel::sync::{deadline, encode_timeout, Timeout};
+use crate::kernel::syscall::{decode, is_privileged, svc_call, Error, SysCall};
+
+/// Counting semaphore. A semaphore with a maximum of 1 is a binary semaphore,
+/// the usual way for an interrupt handler to wake a driver thread:
+/// ```ignore
+/// static RX_READY: Semaphore = Semaphore::new(0, 1);
+///
+/// // in the interrupt handler
+/// let _ = RX_READY.give_from_isr();
+///
+/// // in the driver thread
+/// RX_READY.take(None)?;
+/// ```
+pub struct Semaphore {
+    count: Cell<usize>,
+    max: usize,
+}
+
+// Note(unsafe): the count is only touched by the kernel, in a service or with
+// interrupts disabled.
+unsafe impl Sync for Semaphore {}
+
+impl Semaphore {
+    /// Creates a semaphore holding `initial` of at most `max` permits.
+    /// `initial` is capped at `max`.
+    pub const fn new(initial: usize, max: usize) -> Self {
+        Semaphore {
+            count: Cell::new(if initial < max { initial } else { max }),
+            max,
+        }
+    }
+
+    /// Takes a permit, blocking at most `timeout` ticks while there is none.
+    pub fn take(&self, timeout: Timeout) -> Result<(), Error> {
+        let ret = svc_call(SysCall::SEM_TAKE, self.key(), encode_timeout(timeout), 0);
+        decode(ret).map(|_| ())
+    }
+
+    /// Takes a permit if there is one, fails with `Error::WouldBlock` otherwise.
+    pub fn try_take(&self) -> Result<(), Error> {
+        self.take(Some(0))
+    }
+
+    /// Returns a permit, or hands it straight to the most important waiting
+    /// thread. Fails with `Error::Full` if the count is already at its maximum.
+    pub fn give(&self) -> Result<(), Error> {
+        decode(svc_call(SysCall::SEM_GIVE, self.key(), 0, 0)).map(|_| ())
+    }
+
+    /// `give` for exception handlers, see the `sync` module.
+    pub fn give_from_isr(&self) -> Result<(), Error> {
+        if !is_privileged() {
+            return self.give();
+        }
+        cortex_m::interrupt::free(|_| {
+            let scheduler = unsafe { &mut *(&raw mut SCHEDULER as *mut Scheduler) };
+            self.release(scheduler).map(|_| ())
+        })
+    }
+
+    /// Permits currently available.
+    pub fn count(&self) -> usize {
+        self.count.get()
+    }
+
+    fn key(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Kernel side of `take`.
+    pub(crate) fn acquire(&self, scheduler: &mut Scheduler, timeout: usize) -> Result<usize, Error> {
+        let count = self.count.get();
+        if count > 0 {
+            self.count.set(count - 1);
+            return Ok(0);
+        }
+        if scheduler.current_thread.is_none() {
+            return Err(Error::WouldBlock);
+        }
+
+        let wake_at = deadline(timeout).ok_or(Error::WouldBlock)?;
+        scheduler.block_current(self.key(), wake_at, Error::Timeout.code());
+        SCB::set_pendsv();
+        Ok(0)
+    }
+
+    /// Kernel side of `give` and `give_from_isr`.
+    pub(crate) fn release(&self, scheduler: &mut Scheduler) -> Result<usize, Error> {
+        // a waiting thread means the count is 0, the permit goes to the thread
+        if !scheduler.wake_one(self.key(), |_| 0) {
+            let count = self.count.get();
+            if count == self.max {
+                return Err(Error::Full);
+            }
+            self.count.set(count + 1);
+        }
+        scheduler.reschedule();
+        Ok(0)
+    }
+}
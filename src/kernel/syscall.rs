@@ -1,53 +1,562 @@
 extern crate alloc;
-use cortex_m_semihosting::{hprintln};
 use core::arch::{naked_asm, asm};
+use core::alloc::Layout;
+use core::mem;
+use cortex_m::peripheral::SCB;
+use cortex_m::register::control::{self, Npriv};
+use crate::kernel::allocator::{self, alloc_recorded, free_recorded, HeapError, HeapStats};
+use crate::kernel::allocator::pool::RawPool;
+#[cfg(feature = "mpu")]
+use crate::kernel::mpu;
+use crate::kernel::scheduler::{Scheduler, SCHEDULER};
+use crate::kernel::sync::barrier::Barrier;
+use crate::kernel::sync::condvar::Condvar;
+use crate::kernel::sync::event::{EventGroup, EventWait};
+use crate::kernel::sync::mutex::RawMutex;
+use crate::kernel::sync::notify::{self, NotifyWait};
+use crate::kernel::sync::queue::RawQueue;
+use crate::kernel::sync::rwlock::RawRwLock;
+use crate::kernel::sync::semaphore::Semaphore;
+use crate::kernel::thread::{SpawnError, SpawnSpec, StackFrame, Thread};
+use crate::kernel::timer::{Expired, TimerSpec, Timers, TIMERS};
+use crate::kernel::time;
 
+/// Errors of kernel services. They travel back to the caller in r0, counting
+/// down from `usize::MAX`, so they do not collide with regular results.
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The kernel does not know the service number
+    InvalidService,
+    /// The service rejected its arguments
+    InvalidArgument,
+    /// The wait timed out
+    Timeout,
+    /// The call would have to block, but the timeout was zero
+    WouldBlock,
+    /// The calling thread does not own the object
+    NotOwner,
+    /// The calling thread already holds the non-recursive mutex
+    Deadlock,
+    /// The object can not take any more, e.g. a semaphore at its maximum
+    Full,
+    /// The kernel heap is exhausted
+    OutOfMemory,
+    /// Only privileged code, i.e. `main` and exception handlers, may call this
+    NotPrivileged,
+}
+
+impl Error {
+    const ALL: [Error; 9] = [
+        Error::InvalidService,
+        Error::InvalidArgument,
+        Error::Timeout,
+        Error::WouldBlock,
+        Error::NotOwner,
+        Error::Deadlock,
+        Error::Full,
+        Error::OutOfMemory,
+        Error::NotPrivileged,
+    ];
+
+    /// Value of the error in r0.
+    pub(crate) const fn code(self) -> usize {
+        usize::MAX - self as usize
+    }
+
+    fn from_code(code: usize) -> Option<Error> {
+        Self::ALL.get(usize::MAX - code).copied()
+    }
+}
+
+/// Packs the outcome of a service into r0.
+pub(crate) fn encode(result: Result<usize, Error>) -> usize
+{
+    match result {
+        Ok(value) => value,
+        Err(error) => error.code(),
+    }
+}
 
+/// Unpacks r0 as returned by `svc_call`.
+pub(crate) fn decode(ret: usize) -> Result<usize, Error>
+{
+    match Error::from_code(ret) {
+        Some(error) => Err(error),
+        None => Ok(ret),
+    }
+}
+
+/// Service numbers, passed in r0. Must stay in the order of `SERVICES`.
+#[allow(non_camel_case_types)]
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SysCall {
+    /// Allocate arg0 bytes aligned to arg1 from the kernel heap, returns the
+    /// block or null. With the `mpu` feature the block takes one of the
+    /// caller's `mpu::GRANTS` regions, null once they are all in use
     ALLOC,
+    /// Free the block in arg0, which the calling thread allocated, returns 0
+    /// on success
     FREE,
+    /// Terminate the calling thread
+    EXIT,
+    /// Block the calling thread until the tick in arg0 (low) and arg1 (high)
+    SLEEP,
+    /// Lock the `RawMutex` in arg0, waiting at most the encoded timeout in arg1
+    MUTEX_LOCK,
+    /// Unlock the `RawMutex` in arg0
+    MUTEX_UNLOCK,
+    /// Take a permit of the `Semaphore` in arg0, waiting at most the encoded
+    /// timeout in arg1
+    SEM_TAKE,
+    /// Give a permit to the `Semaphore` in arg0
+    SEM_GIVE,
+    /// Move the item at arg1 into the `RawQueue` in arg0, waiting at most the
+    /// encoded timeout in arg2
+    QUEUE_SEND,
+    /// Move the oldest item of the `RawQueue` in arg0 to arg1, waiting at most
+    /// the encoded timeout in arg2
+    QUEUE_RECV,
+    /// Wait on the `EventGroup` in arg0 as described by the `EventWait` in
+    /// arg1
+    EVENT_WAIT,
+    /// Set the bits in arg1 of the `EventGroup` in arg0
+    EVENT_SET,
+    /// Clear the bits in arg1 of the `EventGroup` in arg0
+    EVENT_CLEAR,
+    /// Notify the thread with id arg0, arg1 and arg2 are the encoded `Notify`
+    NOTIFY,
+    /// Wait for a notification as described by the `NotifyWait` in arg0
+    NOTIFY_WAIT,
+    /// Create a timer from the `TimerSpec` in arg0, returns its id
+    TIMER_CREATE,
+    /// Apply the timer command in arg1 to the timer with id arg0, arg2 is the
+    /// new period of a re-arm
+    TIMER_CONTROL,
+    /// Service thread only: write the next expired timer to the `Expired` in
+    /// arg0, blocks until there is one
+    TIMER_WAIT,
+    /// Unlock the `RawMutex` in arg1 and wait on the `Condvar` in arg0 for at
+    /// most the encoded timeout in arg2
+    CONDVAR_WAIT,
+    /// Notify one thread waiting on the `Condvar` in arg0, all if arg1 is 1
+    CONDVAR_NOTIFY,
+    /// Lock the `RawRwLock` in arg0 for reading, waiting at most the encoded
+    /// timeout in arg1
+    RWLOCK_READ,
+    /// Lock the `RawRwLock` in arg0 for writing, waiting at most the encoded
+    /// timeout in arg1
+    RWLOCK_WRITE,
+    /// Unlock the `RawRwLock` in arg0
+    RWLOCK_UNLOCK,
+    /// Wait on the `Barrier` in arg0, returns 1 in the last thread to arrive
+    BARRIER_WAIT,
+    /// Take a block of the `RawPool` in arg0, waiting at most the encoded
+    /// timeout in arg1, returns the block
+    POOL_ALLOC,
+    /// Return the block in arg1 to the `RawPool` in arg0
+    POOL_FREE,
+    /// Write the usage of the kernel heap to the `HeapStats` in arg0
+    HEAP_STATS,
+    /// Check the kernel heap, writes the outcome to the
+    /// `Result<(), HeapError>` in arg0
+    HEAP_CHECK,
+    /// Spawn a thread as described by the `SpawnSpec` in arg0, writes the
+    /// outcome to the `Result<Thread, SpawnError>` in arg1
+    SPAWN,
+    /// Write the ticks the thread with id arg0 was running to the `u64` in
+    /// arg1
+    CPU_TICKS,
+    /// Return the most stack bytes the thread with id arg0 used so far
+    STACK_HIGH_WATER,
 }
 
+/// Kernel side of a service, gets r1-r3 of the caller and returns its r0.
+type Service = fn(arg0: usize, arg1: usize, arg2: usize) -> usize;
+
+/// Dispatch table, indexed by `SysCall`.
+static SERVICES: [Service; 31] = [
+    sys_alloc,
+    sys_free,
+    sys_exit,
+    sys_sleep,
+    sys_mutex_lock,
+    sys_mutex_unlock,
+    sys_sem_take,
+    sys_sem_give,
+    sys_queue_send,
+    sys_queue_recv,
+    sys_event_wait,
+    sys_event_set,
+    sys_event_clear,
+    sys_notify,
+    sys_notify_wait,
+    sys_timer_create,
+    sys_timer_control,
+    sys_timer_wait,
+    sys_condvar_wait,
+    sys_condvar_notify,
+    sys_rwlock_read,
+    sys_rwlock_write,
+    sys_rwlock_unlock,
+    sys_barrier_wait,
+    sys_pool_alloc,
+    sys_pool_free,
+    sys_heap_stats,
+    sys_heap_check,
+    sys_spawn,
+    sys_cpu_ticks,
+    sys_stack_high_water,
+];
+
 // System call inteface.
+//
+// Passes the exception frame of the caller to `syscall_handler`, from the
+// process stack for threads or the main stack for code running before the
+// scheduler was started. The handler returns straight to the caller as `lr`
+// still holds the exception return value.
 #[no_mangle]
 #[unsafe(naked)]
-unsafe extern "C" fn SVCall() 
+unsafe extern "C" fn SVCall()
 {
     naked_asm!(
-        "push {{lr}}",
-        "bl syscall_handler",
-        "mov r4, r0", 
-        "pop {{lr}}",
-        "bx lr",
+        "tst lr, #4",
+        "ite eq",
+        "mrseq r0, msp",
+        "mrsne r0, psp",
+        "b syscall_handler",
     );
 }
 
-#[allow(unused_variables)]
+/// Decodes the service number and arguments from the caller's stacked r0-r3 and
+/// writes the result back to its stacked r0, so it is in r0 once the exception
+/// returns.
 #[no_mangle]
-pub extern "Rust" fn syscall_handler(
-    service: SysCall,
-    arg0: usize,
-    arg1: usize,
-    arg2: usize,
-) {
-
-    hprintln!("Handler");
-    match service {
-        SysCall::ALLOC => {
-            hprintln!("ALLOC");
-            
-        },
-        SysCall::FREE => {
-            hprintln!("FREE");
+extern "C" fn syscall_handler(frame: &mut StackFrame)
+{
+    let service = frame.r0 as usize;
+    let (arg0, arg1, arg2) = (frame.r1 as usize, frame.r2 as usize, frame.r3 as usize);
+
+    frame.r0 = match SERVICES.get(service) {
+        Some(handler) => handler(arg0, arg1, arg2),
+        None => Error::InvalidService.code(),
+    } as u32;
+}
+
+fn scheduler() -> &'static mut Scheduler
+{
+    // Note(unsafe): services run in the SVCall handler, nothing else touches
+    // the scheduler meanwhile.
+    unsafe { &mut *(&raw mut SCHEDULER as *mut Scheduler) }
+}
+
+/// Checks a pointer a thread passed to a service: it must not be null, must be
+/// aligned to `align` and, with the `mpu` feature, the thread must be able to
+/// access the `size` bytes behind it itself. Services run privileged, without
+/// the check a thread could make them read or write kernel memory.
+fn check(addr: usize, size: usize, align: usize, write: bool) -> Result<(), Error>
+{
+    if addr == 0 || !addr.is_multiple_of(align) {
+        return Err(Error::InvalidArgument);
+    }
+    #[cfg(feature = "mpu")]
+    if let Some(current) = scheduler().current_thread.as_ref() {
+        if !mpu::accessible(current, addr, size, write) {
+            return Err(Error::InvalidArgument);
         }
     }
-    hprintln!("{}, {}, {}", arg0, arg1, arg2);
+    #[cfg(not(feature = "mpu"))]
+    let _ = (size, write);
+    Ok(())
+}
+
+/// A kernel object at an address passed by the caller, see `check`. The
+/// kernel updates objects, so the caller needs write access.
+fn object<T>(addr: usize) -> Result<&'static T, Error>
+{
+    check(addr, mem::size_of::<T>(), mem::align_of::<T>(), true)?;
+    // Note(unsafe): checked above, objects outlive the services working on them.
+    Ok(unsafe { &*(addr as *const T) })
+}
+
+/// A value the service fills in or updates for the caller, see `check`.
+fn out<T>(addr: usize) -> Result<&'static mut T, Error>
+{
+    check(addr, mem::size_of::<T>(), mem::align_of::<T>(), true)?;
+    // Note(unsafe): checked above, the caller waits for the result.
+    Ok(unsafe { &mut *(addr as *mut T) })
+}
+
+/// A value the service only reads, see `check`.
+fn input<T>(addr: usize) -> Result<&'static T, Error>
+{
+    check(addr, mem::size_of::<T>(), mem::align_of::<T>(), false)?;
+    // Note(unsafe): checked above, the caller waits for the result.
+    Ok(unsafe { &*(addr as *const T) })
+}
+
+/// A queue together with its storage, see `check`.
+fn queue(addr: usize) -> Result<&'static RawQueue, Error>
+{
+    let queue = object::<RawQueue>(addr)?;
+    let extent = queue.extent().ok_or(Error::InvalidArgument)?;
+    check(addr, extent, 1, true)?;
+    Ok(queue)
+}
+
+/// A pool together with its blocks, see `check`.
+fn pool(addr: usize) -> Result<&'static RawPool, Error>
+{
+    let pool = object::<RawPool>(addr)?;
+    let extent = pool.extent().ok_or(Error::InvalidArgument)?;
+    check(addr, extent, 1, true)?;
+    Ok(pool)
+}
+
+/// See `alloc_recorded`, with the `mpu` feature a thread holds at most
+/// `mpu::GRANTS` blocks.
+fn sys_alloc(size: usize, align: usize, _: usize) -> usize
+{
+    let layout = match Layout::from_size_align(size, align) {
+        Ok(layout) => layout,
+        Err(_) => return 0,
+    };
+    match scheduler().current_thread.as_mut() {
+        Some(current) => alloc_recorded(Some(current), layout) as usize,
+        None => 0,
+    }
+}
+
+fn sys_free(ptr: usize, _: usize, _: usize) -> usize
+{
+    let free = || {
+        check(ptr, 1, 1, true)?;
+        let current = scheduler().current_thread.as_mut().ok_or(Error::NotOwner)?;
+        if free_recorded(current, ptr as *mut u8) { Ok(0) } else { Err(Error::NotOwner) }
+    };
+    encode(free())
+}
+
+fn sys_exit(_: usize, _: usize, _: usize) -> usize
+{
+    let scheduler = scheduler();
+    scheduler.terminate_current();
+    SCB::set_pendsv();
+    0
+}
+
+fn sys_sleep(low: usize, high: usize, _: usize) -> usize
+{
+    let wake_at = (high as u64) << 32 | low as u64;
+    if wake_at > time::now() {
+        scheduler().sleep_current(wake_at);
+        SCB::set_pendsv();
+    }
+    0
+}
+
+fn sys_mutex_lock(mutex: usize, timeout: usize, _: usize) -> usize
+{
+    encode(object::<RawMutex>(mutex).and_then(|mutex| mutex.lock(scheduler(), timeout)))
+}
+
+fn sys_mutex_unlock(mutex: usize, _: usize, _: usize) -> usize
+{
+    encode(object::<RawMutex>(mutex).and_then(|mutex| mutex.unlock(scheduler())))
+}
+
+fn sys_sem_take(semaphore: usize, timeout: usize, _: usize) -> usize
+{
+    encode(object::<Semaphore>(semaphore).and_then(|semaphore| semaphore.acquire(scheduler(), timeout)))
+}
+
+fn sys_sem_give(semaphore: usize, _: usize, _: usize) -> usize
+{
+    encode(object::<Semaphore>(semaphore).and_then(|semaphore| semaphore.release(scheduler())))
+}
+
+fn sys_queue_send(queue: usize, item: usize, timeout: usize) -> usize
+{
+    let send = || {
+        let queue = self::queue(queue)?;
+        check(item, queue.item_size(), 1, false)?;
+        queue.send(scheduler(), item, timeout)
+    };
+    encode(send())
+}
+
+fn sys_queue_recv(queue: usize, out: usize, timeout: usize) -> usize
+{
+    let recv = || {
+        let queue = self::queue(queue)?;
+        check(out, queue.item_size(), 1, true)?;
+        queue.recv(scheduler(), out, timeout)
+    };
+    encode(recv())
+}
+
+fn sys_event_wait(group: usize, wait: usize, _: usize) -> usize
+{
+    let wait_for = || object::<EventGroup>(group)?.wait_for(scheduler(), out::<EventWait>(wait)?);
+    encode(wait_for())
+}
+
+fn sys_event_set(group: usize, bits: usize, _: usize) -> usize
+{
+    encode(object::<EventGroup>(group).map(|group| {
+        group.update(scheduler(), bits as u32);
+        0
+    }))
+}
+
+fn sys_event_clear(group: usize, bits: usize, _: usize) -> usize
+{
+    encode(object::<EventGroup>(group).map(|group| {
+        group.clear_bits(bits as u32);
+        0
+    }))
+}
+
+fn sys_notify(id: usize, kind: usize, value: usize) -> usize
+{
+    encode(notify::send_encoded(scheduler(), id, kind, value))
+}
+
+fn sys_notify_wait(wait: usize, _: usize, _: usize) -> usize
+{
+    encode(out::<NotifyWait>(wait).and_then(|wait| notify::wait_for(scheduler(), wait)))
+}
+
+fn timers() -> &'static mut Timers
+{
+    let timers = &raw mut TIMERS;
+    // Note(unsafe): only services touch the timers.
+    unsafe { &mut *timers }
+}
+
+fn sys_timer_create(spec: usize, _: usize, _: usize) -> usize
+{
+    let thread = scheduler().current_thread.as_ref().map(|current| current.id);
+    encode(input::<TimerSpec>(spec).and_then(|spec| timers().create(spec, thread)))
+}
+
+fn sys_timer_control(id: usize, command: usize, period: usize) -> usize
+{
+    encode(timers().control(scheduler(), id, command, period))
+}
+
+fn sys_timer_wait(expired: usize, _: usize, _: usize) -> usize
+{
+    encode(out::<Expired>(expired).and_then(|expired| timers().next_expired(scheduler(), expired)))
+}
+
+fn sys_condvar_wait(condvar: usize, mutex: usize, timeout: usize) -> usize
+{
+    let wait_on = || object::<Condvar>(condvar)?.wait_on(scheduler(), object::<RawMutex>(mutex)?, timeout);
+    encode(wait_on())
+}
+
+fn sys_condvar_notify(condvar: usize, all: usize, _: usize) -> usize
+{
+    encode(object::<Condvar>(condvar).map(|condvar| {
+        condvar.notify(scheduler(), all == 1);
+        0
+    }))
+}
+
+fn sys_rwlock_read(lock: usize, timeout: usize, _: usize) -> usize
+{
+    encode(object::<RawRwLock>(lock).and_then(|lock| lock.read(scheduler(), timeout)))
+}
+
+fn sys_rwlock_write(lock: usize, timeout: usize, _: usize) -> usize
+{
+    encode(object::<RawRwLock>(lock).and_then(|lock| lock.write(scheduler(), timeout)))
+}
+
+fn sys_rwlock_unlock(lock: usize, _: usize, _: usize) -> usize
+{
+    encode(object::<RawRwLock>(lock).and_then(|lock| lock.unlock(scheduler())))
+}
+
+fn sys_barrier_wait(barrier: usize, _: usize, _: usize) -> usize
+{
+    encode(object::<Barrier>(barrier).and_then(|barrier| barrier.arrive(scheduler())))
+}
+
+fn sys_pool_alloc(pool: usize, timeout: usize, _: usize) -> usize
+{
+    encode(self::pool(pool).and_then(|pool| pool.take(scheduler(), timeout)))
+}
+
+fn sys_pool_free(pool: usize, block: usize, _: usize) -> usize
+{
+    encode(self::pool(pool).and_then(|pool| pool.give(scheduler(), block)))
+}
+
+fn sys_heap_stats(out: usize, _: usize, _: usize) -> usize
+{
+    let stats = || {
+        let out = self::out::<HeapStats>(out)?;
+        *out = allocator::stats().ok_or(Error::WouldBlock)?;
+        Ok(0)
+    };
+    encode(stats())
+}
+
+fn sys_heap_check(out: usize, _: usize, _: usize) -> usize
+{
+    encode(self::out::<Result<(), HeapError>>(out).map(|out| {
+        *out = allocator::check();
+        0
+    }))
+}
+
+fn sys_spawn(spec: usize, result: usize, _: usize) -> usize
+{
+    let spawn = || {
+        let spec = input::<SpawnSpec>(spec)?;
+        let result = out::<Result<Thread, SpawnError>>(result)?;
+        // the new thread runs the entry function and keeps the name, the
+        // caller must be able to read both itself
+        check(spec.entry as usize & !1, 2, 2, false)?;
+        check(spec.name.as_ptr() as usize, spec.name.len(), 1, false)?;
+        let requester = scheduler().current_thread.as_ref().map(|current| current.id);
+        *result = spec.spawn(requester);
+        Ok(0)
+    };
+    encode(spawn())
+}
+
+fn sys_cpu_ticks(id: usize, out: usize, _: usize) -> usize
+{
+    let ticks = || {
+        let out = self::out::<u64>(out)?;
+        *out = scheduler().find(id).ok_or(Error::InvalidArgument)?.cpu_ticks;
+        Ok(0)
+    };
+    encode(ticks())
+}
+
+fn sys_stack_high_water(id: usize, _: usize, _: usize) -> usize
+{
+    encode(scheduler().find(id).map(|thread| thread.stack_high_water()).ok_or(Error::InvalidArgument))
+}
+
+/// True in handler mode and for privileged thread mode code, which may use
+/// kernel internals directly instead of going through `svc_call`.
+pub fn is_privileged() -> bool
+{
+    let ipsr: u32;
+    // Note(unsafe): reading IPSR has no side effects.
+    unsafe { asm!("mrs {}, ipsr", out(reg) ipsr, options(nomem, nostack, preserves_flags)) };
+    ipsr != 0 || control::read().npriv() == Npriv::Privileged
 }
 
 #[inline(always)]
-pub fn svc_call(service: SysCall, arg0: usize, arg1: usize, arg2: usize) -> usize 
+pub fn svc_call(service: SysCall, arg0: usize, arg1: usize, arg2: usize) -> usize
 {
-    hprintln!("Svc call");
     let ret: usize;
     unsafe {
         asm!(
@@ -56,9 +565,9 @@ pub fn svc_call(service: SysCall, arg0: usize, arg1: usize, arg2: usize) -> usiz
             in("r1") arg0,
             in("r2") arg1,
             in("r3") arg2,
-            lateout("r0") ret,        
+            lateout("r0") ret,
             options(nostack),
         );
     }
     ret
-}
\ No newline at end of file
+}
@@ -1,32 +1,431 @@
-// #[derive(Debug, Default, Clone, Copy)]
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem;
+use core::ptr;
+use crate::kernel::allocator::{alloc_failed, Allocation, ALLOCATOR};
+#[cfg(feature = "mpu")]
+use crate::kernel::mpu;
+use crate::kernel::scheduler::{Scheduler, PRIORITY_LEVELS, SCHEDULER, TIME_SLICE_TICKS};
+use crate::kernel::sync::mutex::RawMutex;
+use crate::kernel::sync::rwlock;
+use crate::kernel::syscall::{decode, is_privileged, svc_call, Error, SysCall};
+use crate::kernel::time;
+use cortex_m_semihosting::hprintln;
+
+/// Smallest stack `spawn` accepts, the initial register frames alone take 64
+/// bytes.
+pub const MIN_STACK_SIZE: usize = 256;
+
+/// The AAPCS wants the stack pointer 8 byte aligned on function entry.
+const STACK_ALIGN: usize = 8;
+
+/// Program status a thread starts with, only the Thumb bit is set.
+const INITIAL_XPSR: u32 = 0x0100_0000;
+
+/// Fills unused stack, so `stack_high_water` can tell how deep a thread went.
+const STACK_PATTERN: u32 = 0xA5A5_A5A5;
+
+/// Lowest word of every stack, checked on each context switch.
+const STACK_CANARY: u32 = 0xC0DE_57AC;
+
+/// Called on a context switch with the id of a thread that overflowed its
+/// stack. The kernel terminates the thread once the hook returns.
+pub type StackOverflowHook = fn(id: usize);
+
+/// `None` until a hook is set, `report_stack_overflow` is used then.
+#[link_section = ".kernel.stack_overflow_hook"]
+static mut STACK_OVERFLOW_HOOK: Option<StackOverflowHook> = None;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum State {
     RUNNING,
     READY,
-
+    /// Waiting in the blocked list until its wake-up time
+    BLOCKED,
+    /// Exited, waiting for the kernel to free its stack
+    TERMINATED,
 }
 
-pub type TaskFn = fn(arg: *mut usize) -> !;
+pub type TaskFn = fn(arg: *mut usize);
 
 #[repr(C)]
 // #[derive(Debug, Default, Clone, Copy)]
 pub struct Tcb {
     pub id: usize,
     pub sp: *mut u32,
-    
-    priority : u8,
+    /// Lowest address of the stack allocation
+    pub stack: *mut u8,
+    pub stack_size: usize,
+    pub name: &'static str,
+    pub state: State,
+
+    /// Scheduling priority, higher values preempt lower ones. Raised above
+    /// `base_priority` while the thread holds a mutex a more important thread
+    /// waits for.
+    pub priority : u8,
+    /// Priority the thread was spawned with
+    pub base_priority: u8,
+    /// Ticks left until the thread is preempted
+    pub time_slice: u32,
+    /// Tick at which a blocked thread becomes ready again
+    pub wake_at: u64,
+    /// Address of the kernel object a blocked thread waits on, 0 when sleeping
+    pub(crate) wait_on: usize,
+    /// Extra information about the wait, its meaning depends on the object
+    pub(crate) wait_data: usize,
+    /// When the thread blocked, see `Scheduler::first_waiter`
+    pub(crate) wait_seq: u64,
+    /// Mutex a blocked thread waits for, used to pass on priorities
+    pub(crate) wait_mutex: *const RawMutex,
+    /// Read-write locks the thread holds for reading, 0 marks a free slot
+    pub(crate) read_locks: [usize; rwlock::READ_LOCKS],
+    /// Called with `wait_on` after the wait timed out, so the object can
+    /// update its state without the thread
+    pub(crate) wait_timeout: Option<fn(&mut Scheduler, usize)>,
+    /// Written to r0 of the thread when it is resumed after blocking
+    pub(crate) wait_result: Option<usize>,
+    /// Notification word, see `sync::notify`
+    pub(crate) notify_value: u32,
+    /// Set by a notification, cleared once the thread received it
+    pub(crate) notify_pending: bool,
+    /// Ticks the thread was running
+    pub cpu_ticks: u64,
+    /// Records of the heap blocks the thread allocated and did not free yet
+    pub(crate) allocations: *mut Allocation,
+    /// Memory the thread may access besides its stack, see `mpu::grant`
+    #[cfg(feature = "mpu")]
+    pub(crate) regions: [Option<mpu::Region>; mpu::GRANTS],
 }
 
 impl Tcb {
-    pub const fn new(stack: *mut u32, id : usize, priority : u8) -> Self {
-        // This is the "static" variable. It is initialized only once.
-        static COUNTER: usize = 0;
-        
+    pub const fn new(
+        sp: *mut u32,
+        id : usize,
+        priority : u8,
+        name: &'static str,
+        stack: *mut u8,
+        stack_size: usize,
+    ) -> Self {
         Tcb {
-            // Relaxed is fine for sequential code; no overhead.
-            sp: stack,
-            id : id,
-            priority : priority
+            id,
+            sp,
+            stack,
+            stack_size,
+            name,
+            state: State::READY,
+            priority,
+            base_priority: priority,
+            time_slice: TIME_SLICE_TICKS,
+            wake_at: 0,
+            wait_on: 0,
+            wait_data: 0,
+            wait_seq: 0,
+            wait_mutex: ptr::null(),
+            read_locks: [0; rwlock::READ_LOCKS],
+            wait_timeout: None,
+            wait_result: None,
+            notify_value: 0,
+            notify_pending: false,
+            cpu_ticks: 0,
+            allocations: ptr::null_mut(),
+            #[cfg(feature = "mpu")]
+            regions: [None; mpu::GRANTS],
+        }
+    }
+
+    /// True if the thread ran past the end of its stack: its stack pointer
+    /// is below the canary, or the canary was overwritten.
+    pub(crate) fn stack_overflowed(&self) -> bool {
+        let canary = self.stack as *const u32;
+        // Note(unsafe): the canary is the lowest word of the stack allocation.
+        (self.sp as usize) < canary as usize + mem::size_of::<u32>()
+            || unsafe { canary.read_volatile() } != STACK_CANARY
+    }
+
+    /// Most bytes of its stack the thread used so far.
+    pub(crate) fn stack_high_water(&self) -> usize {
+        let words = self.stack_size / mem::size_of::<u32>();
+        let stack = self.stack as *const u32;
+        // Note(unsafe): the words above the canary were filled with the
+        // pattern by `spawn`, the thread only writes from the top down.
+        let untouched = (1..words)
+            .take_while(|&i| unsafe { stack.add(i).read_volatile() } == STACK_PATTERN)
+            .count();
+        self.stack_size - (1 + untouched) * mem::size_of::<u32>()
+    }
+
+    /// Layout the stack was allocated with.
+    pub(crate) fn stack_layout(&self) -> Layout {
+        // Note(unsafe): `spawn` already created a layout from the same values.
+        unsafe { Layout::from_size_align_unchecked(self.stack_size, stack_align(self.stack_size)) }
+    }
+}
+
+/// Handle to a spawned thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Thread {
+    id: usize,
+}
+
+impl Thread {
+    /// Kernel wide unique id of the thread.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    /// Priority is not below `PRIORITY_LEVELS`
+    InvalidPriority,
+    /// Stack is smaller than `MIN_STACK_SIZE` or not allocatable at all
+    InvalidStackSize,
+    /// No heap left for the stack or the thread control block
+    OutOfMemory,
+    /// The calling thread can not access the entry function or the name
+    InvalidArgument,
+}
+
+/// Alignment of a stack of `stack_size` bytes. With the `mpu` feature a stack
+/// is a single MPU region, which is aligned to its size.
+const fn stack_align(stack_size: usize) -> usize
+{
+    if cfg!(feature = "mpu") { stack_size } else { STACK_ALIGN }
+}
+
+/// Creates a thread that starts executing `entry(arg)` once the scheduler picks
+/// it.
+///
+/// `stack_size` is rounded down to a multiple of 8 bytes, with the `mpu`
+/// feature up to a power of two. Threads spawn through `SysCall::SPAWN`, a
+/// failed stack or control block allocation is reported for them then.
+pub fn spawn(
+    entry: TaskFn,
+    arg: *mut usize,
+    stack_size: usize,
+    priority: u8,
+    name: &'static str,
+) -> Result<Thread, SpawnError>
+{
+    let spec = SpawnSpec { entry, arg: arg as usize, stack_size, priority, name };
+    if is_privileged() {
+        return spec.spawn(None);
+    }
+    let mut result = Err(SpawnError::InvalidArgument);
+    decode(svc_call(SysCall::SPAWN, &raw const spec as usize, &raw mut result as usize, 0))
+        .map_err(|_| SpawnError::InvalidArgument)?;
+    result
+}
+
+/// Arguments of `SysCall::SPAWN`.
+#[repr(C)]
+pub(crate) struct SpawnSpec {
+    pub(crate) entry: TaskFn,
+    arg: usize,
+    stack_size: usize,
+    priority: u8,
+    pub(crate) name: &'static str,
+}
+
+impl SpawnSpec {
+    /// Privileged part of `spawn`. `requester` is the thread a failed
+    /// allocation is reported for, `None` for privileged code.
+    pub(crate) fn spawn(&self, requester: Option<usize>) -> Result<Thread, SpawnError>
+    {
+        if self.priority as usize >= PRIORITY_LEVELS {
+            return Err(SpawnError::InvalidPriority);
         }
+        if self.stack_size < MIN_STACK_SIZE {
+            return Err(SpawnError::InvalidStackSize);
+        }
+        let stack_size = if cfg!(feature = "mpu") {
+            self.stack_size.checked_next_power_of_two().ok_or(SpawnError::InvalidStackSize)?
+        } else {
+            self.stack_size & !(STACK_ALIGN - 1)
+        };
+        let layout = Layout::from_size_align(stack_size, stack_align(stack_size))
+            .map_err(|_| SpawnError::InvalidStackSize)?;
+
+        let stack = unsafe { ALLOCATOR.alloc(layout) };
+        if stack.is_null() {
+            alloc_failed(requester, layout);
+            return Err(SpawnError::OutOfMemory);
+        }
+        let sp = unsafe {
+            fill_stack(stack, stack_size);
+            init_stack(stack, stack_size, self.entry, self.arg as u32)
+        };
+
+        cortex_m::interrupt::free(|_| {
+            let scheduler = unsafe { &mut *(&raw mut SCHEDULER as *mut Scheduler) };
+            scheduler.id_counter += 1;
+            let id = scheduler.id_counter;
+
+            let tcb = Tcb::new(sp, id, self.priority, self.name, stack, stack_size);
+            match scheduler.add(tcb, requester) {
+                Ok(()) => Ok(Thread { id }),
+                Err(_) => {
+                    unsafe { ALLOCATOR.dealloc(stack, layout) };
+                    Err(SpawnError::OutOfMemory)
+                }
+            }
+        })
+    }
+}
+
+/// Ticks the thread with the given id was running. Fails with
+/// `Error::InvalidArgument` if it exited.
+///
+/// Walks the scheduler's lists, in a critical section for privileged code and
+/// through `SysCall::CPU_TICKS` for threads. Tasks use `CpuTime` for the total
+/// load.
+pub fn cpu_ticks(id: usize) -> Result<u64, Error>
+{
+    if is_privileged() {
+        return with_thread(id, |thread| thread.cpu_ticks);
+    }
+    let mut ticks = 0;
+    decode(svc_call(SysCall::CPU_TICKS, id, &raw mut ticks as usize, 0))?;
+    Ok(ticks)
+}
+
+/// Most bytes of its stack the thread with the given id used so far. Stacks
+/// are filled with a pattern when the thread is spawned, the mark is where the
+/// pattern ends. Threads go through `SysCall::STACK_HIGH_WATER`, otherwise
+/// like `cpu_ticks`.
+pub fn stack_high_water(id: usize) -> Result<usize, Error>
+{
+    if is_privileged() {
+        return with_thread(id, |thread| thread.stack_high_water());
+    }
+    decode(svc_call(SysCall::STACK_HIGH_WATER, id, 0, 0))
+}
+
+/// Runs `f` on the thread with the given id in a critical section. Privileged
+/// code only, threads use the services.
+fn with_thread<R>(id: usize, f: impl FnOnce(&mut Tcb) -> R) -> Result<R, Error>
+{
+    cortex_m::interrupt::free(|_| {
+        let scheduler = unsafe { &mut *(&raw mut SCHEDULER as *mut Scheduler) };
+        scheduler.find(id).map(f).ok_or(Error::InvalidArgument)
+    })
+}
+
+/// Replaces the hook called when a thread overflowed its stack,
+/// `report_stack_overflow` by default. Threads get `Error::NotPrivileged`.
+pub fn set_stack_overflow_hook(hook: StackOverflowHook) -> Result<(), Error>
+{
+    if !is_privileged() {
+        return Err(Error::NotPrivileged);
+    }
+    cortex_m::interrupt::free(|_| unsafe { STACK_OVERFLOW_HOOK = Some(hook) });
+    Ok(())
+}
+
+/// Default stack overflow hook, prints the thread id.
+pub fn report_stack_overflow(id: usize)
+{
+    hprintln!("thread {} overflowed its stack", id);
+}
+
+pub(crate) fn stack_overflowed(id: usize)
+{
+    // Note(unsafe): only written with interrupts disabled.
+    let hook = unsafe { STACK_OVERFLOW_HOOK };
+    hook.unwrap_or(report_stack_overflow)(id);
+}
+
+/// Terminates the calling thread. Its stack is returned to the heap once the
+/// kernel switched away from it.
+pub fn exit() -> !
+{
+    svc_call(SysCall::EXIT, 0, 0, 0);
+    unreachable!("terminated thread was scheduled again");
+}
+
+/// Blocks the calling thread for `ticks` kernel ticks.
+pub fn sleep_ticks(ticks: u64)
+{
+    sleep_until(time::now().saturating_add(ticks));
+}
+
+/// Blocks the calling thread until the tick counter reaches `tick`. Returns
+/// immediately if that is already the case, which makes it suitable for drift
+/// free periodic loops:
+/// ```ignore
+/// let mut next = time::now();
+/// loop {
+///     next += PERIOD;
+///     sleep_until(next);
+///     /* periodic work */
+/// }
+/// ```
+pub fn sleep_until(tick: u64)
+{
+    svc_call(SysCall::SLEEP, tick as usize, (tick >> 32) as usize, 0);
+}
+
+/// Installed as the link register of every new thread, so returning from the
+/// entry function is the same as `exit`.
+fn thread_return() -> !
+{
+    exit()
+}
+
+/// Writes the canary to the lowest word of a fresh stack and the pattern to
+/// all others.
+unsafe fn fill_stack(stack: *mut u8, stack_size: usize)
+{
+    let stack = stack as *mut u32;
+    unsafe {
+        stack.write(STACK_CANARY);
+        for i in 1..stack_size / mem::size_of::<u32>() {
+            stack.add(i).write(STACK_PATTERN);
+        }
+    }
+}
+
+/// Prepares the register frames at the top of a fresh stack so that the first
+/// context switch to the thread "returns" into `entry` with `arg` in r0.
+///
+/// Top of the stack
+/// xpsr
+/// ...
+/// r0
+/// r11
+/// ...
+/// r4        <- returned stack pointer
+/// Bottom of the stack
+unsafe fn init_stack(stack: *mut u8, stack_size: usize, entry: TaskFn, arg: u32) -> *mut u32
+{
+    unsafe {
+        let top = stack.add(stack_size);
+        let frame = top.sub(mem::size_of::<StackFrame>()) as *mut StackFrame;
+        frame.write(StackFrame {
+            r0: arg,
+            r1: 0,
+            r2: 0,
+            r3: 0,
+            r12: 0,
+            lr: thread_return as *const () as u32,
+            // an exception return takes the state from xpsr, the stacked
+            // pc must not carry the Thumb bit of the function address
+            pc: entry as *const () as u32 & !1,
+            xpsr: INITIAL_XPSR,
+        });
+
+        let extension = (frame as *mut u8).sub(mem::size_of::<StackFrameExtension>())
+            as *mut StackFrameExtension;
+        extension.write(StackFrameExtension {
+            r4: 0,
+            r5: 0,
+            r6: 0,
+            r7: 0,
+            r8: 0,
+            r9: 0,
+            r10: 0,
+            r11: 0,
+        });
+        extension as *mut u32
     }
 }
 
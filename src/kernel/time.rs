@@ -0,0 +1,56 @@
+use core::ptr;
+use cortex_m::peripheral::syst::SystClkSource;
+use cortex_m::peripheral::{SCB, SYST};
+use cortex_m_rt::exception;
+use crate::kernel::scheduler::{Scheduler, SCHEDULER};
+
+/// Core clock the SysTick counts, the LM3S6965 runs from its 12 MHz main
+/// oscillator after reset.
+pub const CORE_CLOCK_HZ: u32 = 12_000_000;
+
+/// Kernel ticks per second.
+pub const TICK_RATE_HZ: u32 = 1_000;
+
+/// Ticks since `init`, only ever written by the SysTick handler. Threads may
+/// read it, but not write it, see `mpu`.
+#[link_section = ".kernel.shared.ticks"]
+static mut TICKS: u64 = 0;
+
+/// Configure the SysTick to fire `TICK_RATE_HZ` times a second.
+pub fn init(syst: &mut SYST)
+{
+    syst.set_reload(CORE_CLOCK_HZ / TICK_RATE_HZ - 1);
+    syst.clear_current();
+    syst.set_clock_source(SystClkSource::Core);
+    syst.enable_interrupt();
+    syst.enable_counter();
+}
+
+/// Current value of the monotonic tick counter.
+pub fn now() -> u64
+{
+    // A 64 bit read is two loads, the tick might land in between. Read until
+    // we get the same value twice.
+    loop {
+        let first = unsafe { ptr::read_volatile(&raw const TICKS) };
+        let second = unsafe { ptr::read_volatile(&raw const TICKS) };
+        if first == second {
+            return first;
+        }
+    }
+}
+
+#[exception]
+fn SysTick()
+{
+    let switch = cortex_m::interrupt::free(|_| unsafe {
+        TICKS += 1;
+        let scheduler = &mut *(&raw mut SCHEDULER as *mut Scheduler);
+        scheduler.wake_expired(TICKS);
+        scheduler.tick()
+    });
+
+    if switch {
+        SCB::set_pendsv();
+    }
+}
@@ -0,0 +1,281 @@
+//! Software timers.
+//!
+//! Running timers are kept in a list sorted by expiry tick. The timer service
+//! thread blocks until the first of them expires, so the only per tick cost is
+//! the scheduler's look at the front of its blocked list, no matter how many
+//! timers run. Callbacks run in the service thread, one after the other, and
+//! may use every blocking call a thread may use.
+//!
+//! ```ignore
+//! // in main, before the scheduler is started
+//! timer::start_service(TIMER_PRIORITY, 512)?;
+//!
+//! let blink = Timer::create(toggle_led, ptr::null_mut(), 500, Mode::AutoReload)?;
+//! blink.start()?;
+//! ```
+
+use alloc::boxed::Box;
+use core::ptr;
+use cortex_m::peripheral::SCB;
+use crate::list::{LinkedList, Node};
+use crate::kernel::allocator::kernel_node;
+use crate::kernel::scheduler::Scheduler;
+use crate::kernel::syscall::{decode, svc_call, Error, SysCall};
+use crate::kernel::thread::{spawn, SpawnError};
+use crate::kernel::time;
+
+/// Called in the timer service thread with the expired timer and the argument
+/// it was created with.
+pub type TimerFn = fn(timer: Timer, arg: *mut usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Stops after expiring once
+    OneShot,
+    /// Expires every period until stopped
+    AutoReload,
+}
+
+/// Handle to a timer created with `Timer::create`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timer {
+    id: usize,
+}
+
+impl Timer {
+    /// Creates a stopped timer that calls `callback(timer, arg)` `period` ticks
+    /// after it was started. Fails with `Error::InvalidArgument` if the period
+    /// is 0 or `start_service` was not called, nothing would call it then.
+    pub fn create(callback: TimerFn, arg: *mut usize, period: u32, mode: Mode) -> Result<Timer, Error> {
+        let spec = TimerSpec {
+            callback,
+            arg: arg as usize,
+            period,
+            auto_reload: mode == Mode::AutoReload,
+        };
+        let id = decode(svc_call(SysCall::TIMER_CREATE, &raw const spec as usize, 0, 0))?;
+        Ok(Timer { id })
+    }
+
+    /// Starts the timer, it expires one period from now. Restarts a running
+    /// timer.
+    pub fn start(&self) -> Result<(), Error> {
+        self.control(Command::Start, 0)
+    }
+
+    pub fn stop(&self) -> Result<(), Error> {
+        self.control(Command::Stop, 0)
+    }
+
+    /// Changes the period and restarts the timer with it.
+    pub fn rearm(&self, period: u32) -> Result<(), Error> {
+        self.control(Command::Rearm, period)
+    }
+
+    /// Stops the timer and frees it. The handle is invalid afterwards.
+    pub fn delete(self) -> Result<(), Error> {
+        self.control(Command::Delete, 0)
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    fn control(&self, command: Command, period: u32) -> Result<(), Error> {
+        let ret = svc_call(SysCall::TIMER_CONTROL, self.id, command as usize, period as usize);
+        decode(ret).map(|_| ())
+    }
+}
+
+/// Spawns the timer service thread. Must be called once, from `main` before
+/// the scheduler is started.
+///
+/// Callbacks run at `priority`, usually above the threads that start timers so
+/// they run in time.
+pub fn start_service(priority: u8, stack_size: usize) -> Result<(), SpawnError> {
+    let thread = spawn(service, ptr::null_mut(), stack_size, priority, "timers")?;
+    unsafe { TIMERS.service = thread.id() };
+    Ok(())
+}
+
+/// Arguments of `SysCall::TIMER_CREATE`.
+#[repr(C)]
+pub(crate) struct TimerSpec {
+    callback: TimerFn,
+    arg: usize,
+    period: u32,
+    auto_reload: bool,
+}
+
+/// Operations of `SysCall::TIMER_CONTROL`.
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    Start,
+    Stop,
+    Rearm,
+    Delete,
+}
+
+impl Command {
+    const ALL: [Command; 4] = [Command::Start, Command::Stop, Command::Rearm, Command::Delete];
+}
+
+/// An expired timer, handed from the kernel to the service thread.
+#[repr(C)]
+pub(crate) struct Expired {
+    id: usize,
+    callback: Option<TimerFn>,
+    arg: usize,
+}
+
+struct TimerCb {
+    id: usize,
+    callback: TimerFn,
+    arg: usize,
+    period: u32,
+    auto_reload: bool,
+    /// Tick the timer expires at while running
+    expires_at: u64,
+}
+
+/// All timers, only touched by timer services.
+pub(crate) struct Timers {
+    /// Running timers, sorted by expiry tick
+    running: LinkedList<TimerCb>,
+    stopped: LinkedList<TimerCb>,
+    id_counter: usize,
+    /// Id of the service thread, 0 until it was spawned
+    service: usize,
+}
+
+#[link_section = ".kernel.timers"]
+pub(crate) static mut TIMERS: Timers = Timers::new();
+
+impl Timers {
+    const fn new() -> Self {
+        Timers {
+            running: LinkedList::new(),
+            stopped: LinkedList::new(),
+            id_counter: 0,
+            service: 0,
+        }
+    }
+
+    /// The service thread waits on the address of the timers.
+    fn key(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Kernel side of `Timer::create`, `thread` asked for the timer.
+    pub(crate) fn create(&mut self, spec: &TimerSpec, thread: Option<usize>) -> Result<usize, Error> {
+        if spec.period == 0 || self.service == 0 {
+            return Err(Error::InvalidArgument);
+        }
+        self.id_counter += 1;
+        let node = kernel_node(thread, TimerCb {
+            id: self.id_counter,
+            callback: spec.callback,
+            arg: spec.arg,
+            period: spec.period,
+            auto_reload: spec.auto_reload,
+            expires_at: 0,
+        })
+        .map_err(|_| Error::OutOfMemory)?;
+        self.stopped.push_back(node);
+        Ok(self.id_counter)
+    }
+
+    /// Kernel side of the `Timer` operations.
+    pub(crate) fn control(&mut self, scheduler: &mut Scheduler, id: usize, command: usize, period: usize) -> Result<usize, Error> {
+        let command = Command::ALL.get(command).copied().ok_or(Error::InvalidArgument)?;
+        if command == Command::Rearm && period == 0 {
+            return Err(Error::InvalidArgument);
+        }
+        let mut node = self.take(id).ok_or(Error::InvalidArgument)?;
+
+        match command {
+            Command::Start | Command::Rearm => {
+                if command == Command::Rearm {
+                    node.period = period as u32;
+                }
+                node.expires_at = time::now() + node.period as u64;
+                self.arm(node);
+                // the service thread might sleep past the new expiry
+                scheduler.wake_one(self.key(), |_| Error::WouldBlock.code());
+                scheduler.reschedule();
+            }
+            Command::Stop => self.stopped.push_back(node),
+            Command::Delete => drop(node),
+        }
+        Ok(0)
+    }
+
+    /// Kernel side of the service thread's wait. Hands out the first expired
+    /// timer or blocks the service thread until it expires.
+    pub(crate) fn next_expired(&mut self, scheduler: &mut Scheduler, out: &mut Expired) -> Result<usize, Error> {
+        if scheduler.current_thread.as_ref().is_none_or(|current| current.id != self.service) {
+            return Err(Error::NotOwner);
+        }
+        let now = time::now();
+        let expires_at = self.running.front().map_or(u64::MAX, |timer| timer.expires_at);
+        if expires_at > now {
+            scheduler.block_current(self.key(), expires_at, Error::WouldBlock.code());
+            SCB::set_pendsv();
+            // woken or not, the service thread asks again
+            return Ok(0);
+        }
+
+        let mut node = self.running.pop_front().unwrap();
+        *out = Expired {
+            id: node.id,
+            callback: Some(node.callback),
+            arg: node.arg,
+        };
+        if node.auto_reload {
+            // relative to the last expiry, so the period does not drift
+            node.expires_at += node.period as u64;
+            self.arm(node);
+        } else {
+            self.stopped.push_back(node);
+        }
+        Ok(0)
+    }
+
+    /// Inserts a timer into the running list behind those expiring earlier or
+    /// at the same tick.
+    fn arm(&mut self, node: Box<Node<TimerCb>>) {
+        self.running
+            .insert_when(node, |arming, timer| arming.expires_at < timer.expires_at);
+    }
+
+    /// Removes the timer with the given id from whichever list holds it.
+    fn take(&mut self, id: usize) -> Option<Box<Node<TimerCb>>> {
+        for list in [&self.running, &self.stopped] {
+            let mut cursor = list.cursor_front_mut();
+            while let Some(timer) = cursor.inner() {
+                if timer.id == id {
+                    return cursor.take();
+                }
+                cursor.move_next();
+            }
+        }
+        None
+    }
+}
+
+/// Entry of the timer service thread.
+fn service(_arg: *mut usize)
+{
+    loop {
+        let mut expired = Expired {
+            id: 0,
+            callback: None,
+            arg: 0,
+        };
+        let ret = svc_call(SysCall::TIMER_WAIT, &raw mut expired as usize, 0, 0);
+        if let (Ok(_), Some(callback)) = (decode(ret), expired.callback) {
+            callback(Timer { id: expired.id }, expired.arg as *mut usize);
+        }
+    }
+}
@@ -11,10 +11,10 @@
 //! mind, the linked list uses atomic operations. However, these are not safe
 //! yet. Use a critical section when accessing the linked list.
 
-#![allow(unused)]
+#![allow(unused, clippy::mut_from_ref, clippy::explicit_auto_deref)]
 
-use crate::alloc::allocator::{AllocError, Allocator};
-use crate::mem::boxed::Box;
+use alloc::alloc::{alloc, Layout};
+use alloc::boxed::Box;
 use core::borrow::BorrowMut;
 use core::cell::RefCell;
 use core::marker::PhantomData;
@@ -26,6 +26,10 @@ use core::{mem, ptr};
 
 /******************************************************************************/
 
+/// Returned when a node could not be allocated.
+#[derive(Debug)]
+pub struct AllocError;
+
 type Link<T> = AtomicPtr<Node<T>>;
 
 /// An element/node of a list.
@@ -46,6 +50,23 @@ impl<T> Node<T> {
             next: AtomicPtr::new(ptr::null_mut()),
         }
     }
+
+    /// Move an element into a node on the global heap.
+    ///
+    /// **Note:** In contrast to `Box::new()` this returns an error instead of
+    /// aborting when we're out of memory.
+    pub fn try_boxed(element: T) -> Result<Box<Self>, AllocError> {
+        // Note(unsafe): The layout is non-zero sized and the pointer is checked
+        // to be non-null before it is written to.
+        unsafe {
+            let node = alloc(Layout::new::<Self>()) as *mut Self;
+            if node.is_null() {
+                return Err(AllocError);
+            }
+            node.write(Node::new(element));
+            Ok(Box::from_raw(node))
+        }
+    }
 }
 
 impl<T> Deref for Node<T> {
@@ -77,13 +98,11 @@ impl<T> DerefMut for Node<T> {
 /// let mut list_b = LinkedList::new();
 /// ```
 ///
-/// Add element to the end of a list with an allocator:
+/// Add element to the end of a list, the node is taken from the global heap:
 /// ```ignore
-/// static ALLOCATOR: Bump = unsafe { Bump::new(NonNull::new_unchecked(0x2001E000 as *mut u8), 5_000) };
-/// list_a.emplace_back(MyStruct { id: 42 }, &ALLOCATOR);
-/// list_a.emplace_back(MyStruct { id: 54 }, &ALLOCATOR);
+/// list_a.emplace_back(MyStruct { id: 42 });
+/// list_a.emplace_back(MyStruct { id: 54 });
 ///```
-/// Nodes in the same list can be allocated in different memory sections.
 ///
 /// Move an element from one to another list:
 /// ```ignore
@@ -97,6 +116,12 @@ pub struct LinkedList<T> {
     len: AtomicUsize,
 }
 
+impl<T> Default for LinkedList<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T> LinkedList<T> {
     /// Create an empty list
     pub const fn new() -> Self {
@@ -110,21 +135,15 @@ impl<T> LinkedList<T> {
     /// Allocate a new element and move it to the end of the list
     ///
     /// **Note:** This fails when we're out of memory
-    pub fn emplace_back(
-        &self,
-        element: T,
-        alloc: &'static dyn Allocator,
-    ) -> Result<(), AllocError> {
-        let node = Box::try_new_in(Node::new(element), alloc);
-        // Note(unsafe): map() is only called if the pointer is non-null.
-        node.map(|mut n| unsafe {
-            self.push_back(n);
-        })
+    pub fn emplace_back(&self, element: T) -> Result<(), AllocError> {
+        let node = Node::try_boxed(element)?;
+        self.push_back(node);
+        Ok(())
     }
 
     /// Insert a node at the end on the list
     pub fn push_back(&self, mut node: Box<Node<T>>) {
-        let mut node_raw = Box::leak(node);
+        let mut node_raw = NonNull::from(Box::leak(node));
         let mut tail = self.tail.load(Ordering::Acquire);
 
         // Note(unsafe): Pointer requirements are met.
@@ -163,7 +182,7 @@ impl<T> LinkedList<T> {
                 (*node).next.store(ptr::null_mut(), Ordering::Relaxed);
                 (*node).prev.store(ptr::null_mut(), Ordering::Relaxed);
                 self.len.fetch_sub(1, Ordering::Relaxed);
-                Box::from_raw(NonNull::new_unchecked(node))
+                Box::from_raw(node)
             })
         }
     }
@@ -173,7 +192,7 @@ impl<T> LinkedList<T> {
     /// **Note:** prefer [`Self::insert_when()`] if possible
     pub fn insert(&self, node: NonNull<Node<T>>, mut new_node: Box<Node<T>>) {
         let node_ptr = node;
-        let new_node_ptr = Box::leak(new_node);
+        let new_node_ptr = NonNull::from(Box::leak(new_node));
 
         // Note(unsafe): Pointer requirements are met.
         unsafe {
@@ -264,12 +283,17 @@ impl<T> LinkedList<T> {
         self.len.load(Ordering::Relaxed)
     }
 
+    /// True if the list has no nodes
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     /// Remove a node from any point in the list.
     ///
     /// # Safety
     /// A node is only allowed to be unliked once.
     unsafe fn unlink(&self, node: Box<Node<T>>) -> Box<Node<T>> {
-        self.unlink_raw(Box::leak(node))
+        self.unlink_raw(NonNull::from(Box::leak(node)))
     }
 
     /// Remove a node from any point in the list.
@@ -299,7 +323,7 @@ impl<T> LinkedList<T> {
             .store(ptr::null_mut(), Ordering::Relaxed);
         self.len.fetch_sub(1, Ordering::Relaxed);
 
-        Box::from_raw(node)
+        Box::from_raw(node.as_ptr())
     }
 
     /// Provides a forward iterator.
@@ -2,156 +2,71 @@
 #![no_main]
 
 pub mod kernel;
+#[path = "lib/list.rs"]
+pub mod list;
 use panic_halt as _; 
 extern crate alloc;
+use alloc::vec::Vec;
 use cortex_m_rt::entry;
-use cortex_m_semihosting::{debug, hprintln};
-use core::alloc::{GlobalAlloc, Layout};
-use core::mem;
-use core::arch::{naked_asm, asm};
-use cortex_m_rt::exception;
+use cortex_m_semihosting::hprintln;
 use core::mem::MaybeUninit;
-use kernel::scheduler::Scheduler;
-use kernel::allocator::LinkedListAllocator;
-use kernel::allocator::Locked;
-use kernel::thread::{Tcb, TaskFn};
-use kernel::thread::{StackFrameExtension, StackFrame};
+use core::ptr;
+use kernel::scheduler::{CpuTime, Scheduler, SCHEDULER};
+use kernel::thread::{sleep_ticks, sleep_until, spawn};
+use kernel::time;
 
-#[global_allocator]
-static ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
-static mut SCHEDULER: MaybeUninit<Scheduler> = MaybeUninit::uninit();
-
-extern "C" 
+fn task1(_arg : *mut usize)
 {
-    static mut _heap_start: u8;
-    static mut _heap_end:   u8;
-}
-
-fn task1(arg : *mut usize) -> !
-{
-    // hprintln!("Entering task1 function");
-    let test_va : u128 = 12423123;
     loop {
-
+        hprintln!("task1 at tick {}", time::now());
+        sleep_ticks(500);
     }
 }
 
-fn task2(arg : *mut usize) -> !
+fn task2(_arg : *mut usize)
 {
-     let test_va : u128 = 12423123;
-    // hprintln!("Entering task2 function");
-    //  let scheduler =  unsafe {&mut *SCHEDULER.as_mut_ptr()};
-    // for thread in scheduler.threads.iter_mut() 
-    // {
-    //     hprintln!("Thread id: {}, stack pointer: {:p}", thread.id, thread.sp);
-    // }
-
+    // periodic without drift, the print does not shift the period
+    let mut next = time::now();
+    let mut last = CpuTime::now();
     loop {
+        next += 1000;
+        sleep_until(next);
 
+        let cpu = CpuTime::now();
+        hprintln!("task2 at tick {}, cpu load {}%", time::now(), cpu.load_since(&last));
+        last = cpu;
     }
 }
 
-fn task3(arg : *mut usize) -> !
+fn task3(_arg : *mut usize)
 {
-     let test_va : u128 = 12423123;
-    // hprintln!("Entering task3 function");
-    //  let scheduler =  unsafe {&mut *SCHEDULER.as_mut_ptr()};
-    // for thread in scheduler.threads.iter_mut() 
-    // {
-    //     hprintln!("Thread id: {}, stack pointer: {:p}", thread.id, thread.sp);
-    // }
-
-    loop {
-
+    // heap allocations of threads go through the kernel, which grants the
+    // blocks to the thread with the mpu feature
+    let mut wake_ups = Vec::new();
+    for _ in 0..5 {
+        wake_ups.push(time::now());
+        sleep_ticks(2000);
     }
+    hprintln!("task3 woke up at {:?}", wake_ups);
 }
 
-fn task_exit_error() -> ! {
-    
-    hprintln!("Task exited\n");
-
-    loop {
-        // panic, halt, or delete task
-    }
-}
-
-fn start_first_task() -> () {
-    let stack_ptr : *mut u32;
-    unsafe {
-        let scheduler =  &mut *SCHEDULER.as_mut_ptr();
-        let current_thread = scheduler.threads.pop_front();
-
-        if (current_thread.is_none())
-        {
-            return;
-        }
-        scheduler.current_thread = current_thread;
-        stack_ptr = scheduler.current_thread.as_mut().unwrap().sp;
-    }
+// With the MPU a stack is aligned to its size, three of 1 KiB would not fit the
+// 4 KiB heap next to the thread control blocks
+const TASK_STACK_SIZE: usize = if cfg!(feature = "mpu") { 512 } else { 1024 };
 
-    unsafe {
-        asm!(
-        "msr psp, r0",
-        "movs r0, #2",
-        "msr control, r0",
-        "isb",
-        "pop   {{r4-r11}}",
-        "pop   {{r0-r3,r12,lr}}",   // force function entry
-        "pop   {{pc}}",             // 'jump' to the task entry function we put on the stack
-        in("r0") stack_ptr as u32,
-        options(noreturn),
-        )
-    }
-}
-
-fn task_init(entry : TaskFn)
+#[cfg_attr(feature = "self-test", allow(dead_code))]
+fn spawn_tasks()
 {
-    unsafe {
-        let layout = Layout::from_size_align(1024, size_of::<usize>()).expect("Invalid layout");;  
-        let stack_ptr = ALLOCATOR.alloc(layout) as *mut usize;
-        let mut highest_ptr = stack_ptr.offset(64);
-        let mut stack_offset = mem::size_of::<StackFrame>() / mem::size_of::<usize>();
-
-        // Top of the stack
-        // xpsr
-        //
-        // ...
-        // r0
-        // r11
-        // R4
-        // Bottom of the stack
-
-        let mut stack_frame2: &mut StackFrame = mem::transmute(&mut *highest_ptr.offset(-(stack_offset as isize)));
-        // let mut r3_r12 = highest_ptr.offset(-(stack_offset as isize));
-        stack_frame2.xpsr = 0x01000000;
-        stack_frame2.lr = 0xFFFFFFFD;
-        stack_frame2.pc = entry as u32;
-        stack_frame2.r0 = 0;
-        stack_frame2.r1 = 0;
-        stack_frame2.r3 = 0;
-        stack_frame2.r2 = 0;
-        stack_frame2.r12 = 0;
-
-        stack_offset += mem::size_of::<StackFrameExtension>() / mem::size_of::<usize>();
-        let sp = highest_ptr.offset(-(stack_offset as isize)) as *mut u32;
-
-        let scheduler =  &mut *SCHEDULER.as_mut_ptr();
-        scheduler.id_counter += 1;
-        scheduler.threads.push_back(Tcb::new(sp, scheduler.id_counter, 1));
-    }
+    spawn(task1, ptr::null_mut(), TASK_STACK_SIZE, 1, "task1").expect("Failed to spawn task1");
+    spawn(task2, ptr::null_mut(), TASK_STACK_SIZE, 1, "task2").expect("Failed to spawn task2");
+    spawn(task3, ptr::null_mut(), TASK_STACK_SIZE, 1, "task3").expect("Failed to spawn task3");
 }
 
 #[entry]
 fn main() -> ! 
 {    
 
-    // Initialise sys tick timer
     let mut peripheral = unsafe { cortex_m::Peripherals::steal() };
-    peripheral.SYST.set_reload(200_000_000 - 1);
-    peripheral.SYST.clear_current();
-    peripheral.SYST.set_clock_source(cortex_m::peripheral::syst::SystClkSource::Core);
-    peripheral.SYST.enable_interrupt();
-    peripheral.SYST.enable_counter();
 
     unsafe {
         SCHEDULER = MaybeUninit::new(Scheduler::new());
@@ -159,23 +74,21 @@ fn main() -> !
             .SCB
             .set_priority(cortex_m::peripheral::scb::SystemHandler::PendSV, 0xFF);
 
-        ALLOCATOR.lock().init(&raw mut _heap_start as *mut u8 as usize, 4096);
-
-        task_init(task1);
-        task_init(task2);
-        task_init(task3);
-
-        hprintln!("Returned to main somehow idfk.");
+        kernel::allocator::init().expect("Heap region overlaps memory in use");
+        #[cfg(feature = "mpu")]
+        kernel::mpu::init();
 
-        start_first_task();
+        // the self tests run instead of the tasks, the kernel exits once they are done
+        #[cfg(feature = "self-test")]
+        {
+            kernel::allocator::self_test::run();
+            kernel::sync::self_test::start().expect("Failed to spawn the sync self test");
+        }
+        #[cfg(not(feature = "self-test"))]
+        spawn_tasks();
     }
-    
-    // cortex_m::peripheral::SCB::set_pendsv();
 
-    // cortex_m::peripheral::SYST::set_reload(&mut self, value);
-
-    debug::exit(debug::EXIT_SUCCESS);
-
-    loop {
-    }
+    // Initialise sys tick timer, its handler works on the scheduler
+    kernel::time::init(&mut peripheral.SYST);
+    kernel::scheduler::start();
 }